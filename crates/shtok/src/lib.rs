@@ -6,114 +6,516 @@ use std::borrow::{
 };
 use std::collections::HashMap;
 use std::{
+    error,
     fmt,
     io,
+    iter,
 };
 
 use Word::*;
 use itertools::Itertools;
 
-/// Procrc entry.
+pub use crate::quote::{
+    join,
+    join_bytes,
+    quote,
+    quote_bytes,
+};
+pub use crate::resolver::{
+    Chain,
+    Env,
+    FromFn,
+    Resolver,
+    from_fn,
+};
+
+mod quote;
+mod resolver;
+
+/// Procrc entry, i.e., a line like `web: PORT=8080 gunicorn -b :$PORT main:app`.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Entry {
+    /// The name given by the Procfile syntax `name: command`.
+    pub name: Option<String>,
+    /// The environment variables assigned before the command, e.g., `PORT=8080`.
+    pub env:  Vec<(String, String)>,
     /// Indicates the command that you should execute such as:
     /// - `gunicorn -b :$PORT main:app`
     /// - `rake jobs:work`
-    pub flag: Vec<String>,
+    ///
+    /// Empty if the line has only a name or assignments.
+    pub argv: Vec<String>,
+    /// The line where the entry starts, from 1.
+    pub line: usize,
+}
+
+/// An error reading or parsing procrc.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to read the source.
+    Io(io::Error),
+    /// The source is not well-formed.
+    Parse(ParseError),
+}
+
+/// A syntax error, located in the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// What is wrong.
+    pub kind:    ParseErrorKind,
+    /// The line of the error, starting from 1.
+    pub line:    usize,
+    /// The column of the error in characters, starting from 1.
+    pub column:  usize,
+    /// The byte offset of the error from the beginning of the source.
+    pub offset:  usize,
+    /// The source from the error until it is detected or the line ends, truncated if too long.
+    pub snippet: String,
 }
 
-/// Parses procrc.
-pub fn parse<R>(source: R, vars: Option<HashMap<String, String>>) -> io::Result<Vec<Entry>>
+/// The kind of a syntax error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParseErrorKind {
+    /// A quote is not closed until the end of the source.
+    UnterminatedQuote(char),
+    /// A `$` is not followed by a variable name.
+    BadVariable,
+    /// A `\` is at the end of the source, with nothing to escape.
+    DanglingEscape,
+    /// A `${` is not closed until the end of the source.
+    UnterminatedBrace,
+    /// The contents of `${...}` are malformed.
+    BadSubstitution,
+    /// A variable is unset in strict mode.
+    Unbound(String),
+    /// `${NAME:?message}` is expanded while the variable is unset.
+    Unset {
+        /// The name of the variable.
+        name:    String,
+        /// The message given after `?`, if any.
+        message: String,
+    },
+}
+
+/// Parses procrc. Comments starting with `#` are skipped.
+///
+/// ```
+/// # use shtok::Entry;
+/// let rc = "# Runs the app.\nweb: PORT=8080 gunicorn main:app # on 8080\n";
+/// let entries = shtok::parse(rc.as_bytes(), None).unwrap();
+/// assert_eq!(
+///     entries,
+///     [Entry {
+///         name: Some("web".to_owned()),
+///         env:  vec![("PORT".to_owned(), "8080".to_owned())],
+///         argv: vec!["gunicorn".to_owned(), "main:app".to_owned()],
+///         line: 2,
+///     }]
+/// );
+/// ```
+pub fn parse<R>(source: R, vars: Option<HashMap<String, String>>) -> Result<Vec<Entry>, Error>
 where
     R: io::Read,
 {
     let rcfile = io::read_to_string(source)?;
-    let tokens = Tokens::new(rcfile.bytes(), vars);
-    Ok(tokens.map(|flag| Entry { flag }).collect())
+    let mut tokens = Tokens::new(rcfile.bytes(), vars);
+    iter::from_fn(|| tokens.next_line()).map(|line| Ok(Entry::new(line?))).collect()
 }
 
+impl Entry {
+    /// Splits the tokens of a line into the name, the assignments and the command.
+    ///
+    /// Names and assignments are recognized only in the unquoted literal prefixes of the tokens,
+    /// so neither `"A=1"` nor `$X` expanded to `A=1` is an assignment, as in the shell.
+    fn new(line: Line) -> Self {
+        let start = line.first().map_or(0, |(token, _)| token.line);
+        let mut tokens = line
+            .into_iter()
+            .map(|(token, arg)| (token.bare, String::from_utf8_lossy(&arg).into_owned()))
+            .collect::<Vec<_>>();
+
+        let name = tokens.first().and_then(|(bare, token)| {
+            let (name, rest) = split_bare(token, *bare, b':', is_entry_name)?;
+            Some((name.to_owned(), rest.to_owned(), bare - name.len() - 1))
+        });
+        let name = name.map(|(name, rest, bare)| {
+            // Allows no space after the colon, e.g., `web:gunicorn`.
+            if rest.is_empty() {
+                tokens.remove(0);
+            } else {
+                tokens[0] = (bare, rest);
+            }
+            name
+        });
+
+        let assignment = |bare: usize, token: &str| {
+            split_bare(token, bare, b'=', is_name)
+                .filter(|(key, _)| !key.starts_with(|c: char| c.is_ascii_digit()))
+                .map(|(key, val)| (key.to_owned(), val.to_owned()))
+        };
+        let assignments =
+            tokens.iter().take_while(|(bare, token)| assignment(*bare, token).is_some()).count();
+        let env = tokens
+            .drain(..assignments)
+            .map(|(bare, token)| assignment(bare, &token).unwrap())
+            .collect();
+
+        let argv = tokens.into_iter().map(|(_, token)| token).collect();
+        Entry { name, env, argv, line: start }
+    }
+}
+
+/// Whether the byte can be in the name of an entry.
+fn is_entry_name(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'-'
+}
+
+/// Splits the token at the separator following a key, if both are in the bare prefix.
+fn split_bare(
+    token: &str,
+    bare: usize,
+    sep: u8,
+    is_key: impl Fn(u8) -> bool,
+) -> Option<(&str, &str)> {
+    let len = token.bytes().take_while(|&b| is_key(b)).count();
+    let found = len > 0 && len < bare && token.as_bytes().get(len) == Some(&sep);
+    found.then(|| (&token[..len], &token[len + 1..]))
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => err.fmt(f),
+            Error::Parse(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Parse(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::Parse(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            Error::Parse(err) => err.into(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at line {}, column {}: {}", self.kind, self.line, self.column, self.snippet)
+    }
+}
+
+impl error::Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(err: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnterminatedQuote(quote) => write!(f, "unterminated quote {quote}"),
+            ParseErrorKind::BadVariable => f.write_str("expected a variable name after $"),
+            ParseErrorKind::DanglingEscape => f.write_str("nothing to escape after \\"),
+            ParseErrorKind::UnterminatedBrace => f.write_str("unterminated ${"),
+            ParseErrorKind::BadSubstitution => f.write_str("bad substitution"),
+            ParseErrorKind::Unbound(name) => write!(f, "{name}: unbound variable"),
+            ParseErrorKind::Unset { name, message } if message.is_empty() => {
+                write!(f, "{name}: parameter null or not set")
+            }
+            ParseErrorKind::Unset { name, message } => write!(f, "{name}: {message}"),
+        }
+    }
+}
+
+/// The tokens of a line, each of which is paired with its expansion.
+type Line = Vec<(Token, Vec<u8>)>;
+
 /// Transforms an input bytes into a sequence of tokens.
-pub struct Tokens<I> {
-    lex:  Lexer<I>,
-    vars: HashMap<String, String>,
+pub struct Tokens<I, R = HashMap<String, String>> {
+    lex:      Lexer<I>,
+    resolver: R,
+    /// Variables assigned by `${NAME:=word}`, which take precedence over the resolver.
+    assigned: HashMap<String, String>,
+    strict:   bool,
 }
 
 impl<I> Tokens<I> {
-    /// Creates a new Tokens.
+    /// Creates a new Tokens, which yields the tokens line by line, and stops after an error.
     ///
     /// ```
     /// # use shtok::Tokens;
     /// let mut tokens = Tokens::new("foo\"ba\"r baz".bytes(), None);
-    /// assert_eq!(tokens.next().unwrap(), ["foobar", "baz"]);
+    /// assert_eq!(tokens.next().unwrap().unwrap(), ["foobar", "baz"]);
     /// assert_eq!(tokens.next(), None);
+    ///
+    /// let mut tokens = Tokens::new("foo\n'bar".bytes(), None);
+    /// assert_eq!(tokens.next().unwrap().unwrap(), ["foo"]);
+    /// assert_eq!(
+    ///     tokens.next().unwrap().unwrap_err().to_string(),
+    ///     "unterminated quote ' at line 2, column 1: 'bar"
+    /// );
     /// ```
     pub fn new<T>(bytes: T, vars: Option<HashMap<String, String>>) -> Self
     where
         T: IntoIterator<Item = u8, IntoIter = I>,
     {
-        Tokens { lex: Lexer::new(bytes), vars: vars.unwrap_or_default() }
+        Tokens::with_resolver(bytes, vars.unwrap_or_default())
+    }
+}
+
+impl<I, R> Tokens<I, R>
+where
+    R: Resolver,
+{
+    /// Creates a new Tokens, which looks up variables by the resolver.
+    pub fn with_resolver<T>(bytes: T, resolver: R) -> Self
+    where
+        T: IntoIterator<Item = u8, IntoIter = I>,
+    {
+        Tokens { lex: Lexer::new(bytes), resolver, assigned: HashMap::new(), strict: false }
+    }
+
+    /// Fails on unset variables, instead of expanding them to the empty string.
+    ///
+    /// Variables with a default like `${NAME:-default}` are allowed to be unset.
+    ///
+    /// ```
+    /// # use shtok::Tokens;
+    /// let mut tokens = Tokens::new("$HOME ${PORT:-8080}".bytes(), None).strict(true);
+    /// assert_eq!(
+    ///     tokens.next().unwrap().unwrap_err().to_string(),
+    ///     "HOME: unbound variable at line 1, column 1: $HOME"
+    /// );
+    /// ```
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
     }
 }
 
-impl<I> Iterator for Tokens<I>
+impl<I, R> Iterator for Tokens<I, R>
 where
     I: Iterator<Item = u8>,
+    R: Resolver,
 {
-    type Item = Vec<String>;
+    type Item = Result<Vec<String>, ParseError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let token_to_str = |token: Token| {
-            let mut out = String::new();
-            for word in token.words {
-                match word {
-                    Lit(v) => {
-                        let lit: Cow<str> = String::from_utf8_lossy(&v);
-                        out.push_str(lit.borrow());
-                    }
-                    Var(v) => {
-                        let var: Cow<str> = String::from_utf8_lossy(&v);
-                        let var: &str = var.borrow();
-                        if let Some(var) = self.vars.get(var) {
-                            out.push_str(var);
-                        }
-                    }
-                    NewLine(_) => {
-                        break;
-                    }
-                }
-            }
-            out
-        };
+        let line = self.next_line()?;
+        let arg = |(_, arg): (Token, Vec<u8>)| String::from_utf8_lossy(&arg).into_owned();
+        Some(line.map(|line| line.into_iter().map(arg).collect()))
+    }
+}
+
+impl<I, R> Tokens<I, R>
+where
+    I: Iterator<Item = u8>,
+    R: Resolver,
+{
+    /// Reads the tokens of the next line as bytes, unlike [`next`](Iterator::next), which replaces
+    /// invalid UTF-8 with U+FFFD.
+    ///
+    /// ```
+    /// # use shtok::Tokens;
+    /// let mut tokens = Tokens::new(b"cat '\xff.log'".to_vec(), None);
+    /// assert_eq!(tokens.next_bytes().unwrap().unwrap(), [&b"cat"[..], b"\xff.log"]);
+    /// ```
+    pub fn next_bytes(&mut self) -> Option<Result<Vec<Vec<u8>>, ParseError>> {
+        let line = self.next_line()?;
+        Some(line.map(|line| line.into_iter().map(|(_, arg)| arg).collect()))
+    }
+
+    /// Reads the tokens of the next line.
+    fn next_line(&mut self) -> Option<Result<Line, ParseError>> {
         let tokens = self
             .lex
             .by_ref()
             // TODO: Use iter::take_until or something.
             // Rust stdlib may have this someday.
             // https://github.com/rust-lang/rust/issues/62208
-            .take_while_inclusive(|t| !matches!(t.words.last(), Some(NewLine(_))))
-            .map(token_to_str);
+            .take_while_inclusive(
+                |t| matches!(t, Ok(t) if !matches!(t.words.last(), Some(NewLine(_)))),
+            )
+            .collect::<Result<Vec<_>, _>>();
+        let tokens = match tokens {
+            Ok(tokens) if tokens.is_empty() => return None,
+            Ok(tokens) => tokens,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let mut scope =
+            Scope { resolver: &self.resolver, assigned: &mut self.assigned, strict: self.strict };
+        let line = tokens
+            .into_iter()
+            // A line break after whitespace or a comment is a token alone.
+            .filter(|token| !matches!(token.words[..], [NewLine(_)]))
+            .map(|token| scope.expand_bytes(&token.words).map(|arg| (token, arg)))
+            .collect::<Result<Vec<_>, _>>();
+        // Stops after an error, as the lexer does.
+        self.lex.failed |= line.is_err();
+        Some(line)
+    }
+}
+
+/// Lists the variables referenced by the source in order of appearance, without expanding them.
+///
+/// Variables in the words of operators like `${NAME:-$DEFAULT}` are also listed.
+///
+/// ```
+/// let vars = shtok::referenced_vars("web -b :${PORT:-$DEFAULT_PORT}\nworker $PORT".bytes());
+/// assert_eq!(vars.unwrap(), ["PORT", "DEFAULT_PORT"]);
+/// ```
+pub fn referenced_vars<T>(bytes: T) -> Result<Vec<String>, ParseError>
+where
+    T: IntoIterator<Item = u8>,
+{
+    fn walk(words: &[Word], vars: &mut Vec<String>) {
+        for word in words {
+            match word {
+                Var(var) => vars.push(String::from_utf8_lossy(&var.name).into_owned()),
+                Param(param) => {
+                    vars.push(param.name.clone());
+                    if let Some(op) = &param.op {
+                        walk(&op.word, vars);
+                    }
+                }
+                Lit(_) | NewLine(_) => (),
+            }
+        }
+    }
 
-        let tokens = tokens.collect::<Vec<_>>();
-        if tokens.is_empty() { None } else { Some(tokens) }
+    let mut vars = Vec::new();
+    for token in Lexer::new(bytes) {
+        walk(&token?.words, &mut vars);
+    }
+    Ok(vars.into_iter().unique().collect())
+}
+
+/// The variables seen from expansions.
+struct Scope<'a, R> {
+    resolver: &'a R,
+    assigned: &'a mut HashMap<String, String>,
+    strict:   bool,
+}
+
+impl<R: Resolver> Scope<'_, R> {
+    fn get(&self, name: &str) -> Option<String> {
+        self.assigned.get(name).cloned().or_else(|| self.resolver.resolve(name))
+    }
+
+    /// Expands the words into a string. Unset variables expand to the empty string, unless strict.
+    fn expand(&mut self, words: &[Word]) -> Result<String, ParseError> {
+        Ok(String::from_utf8_lossy(&self.expand_bytes(words)?).into_owned())
+    }
+
+    /// Expands the words into bytes, keeping literals as they are.
+    fn expand_bytes(&mut self, words: &[Word]) -> Result<Vec<u8>, ParseError> {
+        let mut out = Vec::new();
+        for word in words {
+            match word {
+                Lit(v) => {
+                    out.extend_from_slice(v);
+                }
+                Var(var) => {
+                    let name: Cow<str> = String::from_utf8_lossy(&var.name);
+                    match self.get(name.borrow()) {
+                        Some(value) => out.extend_from_slice(value.as_bytes()),
+                        None if self.strict => return Err(var.unbound()),
+                        None => (),
+                    }
+                }
+                Param(param) => {
+                    out.extend_from_slice(param.expand(self)?.as_bytes());
+                }
+                NewLine(_) => {
+                    break;
+                }
+            }
+        }
+        Ok(out)
     }
 }
 
 struct Lexer<I> {
-    bytes: I,
-    state: State,
+    bytes:   I,
+    state:   State,
+    /// The position of the next byte.
+    next:    Pos,
+    /// Where the current quote started, reported if it is not closed.
+    quote:   Mark,
+    /// Where the current escape or variable started.
+    mark:    Mark,
+    /// Finds the end of the current `${...}`, whose contents are collected into braced.
+    scan:    BraceScan,
+    braced:  Vec<u8>,
+    /// Whether tokens have been found since the last line break.
+    pending: bool,
+    failed:  bool,
+}
+
+/// A position in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Pos {
+    offset: usize,
+    line:   usize,
+    column: usize,
+}
+
+/// A position with the source following it, to be reported in errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Mark {
+    pos:     Pos,
+    snippet: Vec<u8>,
+    /// Whether the snippet is still growing, until the end of the line.
+    open:    bool,
 }
 
+/// The maximum length of snippets in bytes.
+const SNIPPET_LEN: usize = 40;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum State {
     // Skipping non ascii whitespaces.
     FindNextNonAsciiWhiteSpace = 0,
+    // Found "#" at the beginning of a token.
+    Comment = 10,
     // Not in any of the following states.
     NoQuote = 1,
     // Found "\".
     NoQuoteEscape = 2,
+    // Found "\r" after "\".
+    NoQuoteEscapeCarriageReturn = 6,
     // Found "$".
     NoQuoteVarStart = 3,
     NoQuoteVar = 4,
+    // Found "${".
+    NoQuoteBrace = 5,
     // Found "'", but an another matching quote yet.
     InSingleQuote = 30,
     // Found '"', but an another matching quote yet.
@@ -123,14 +525,22 @@ pub(crate) enum State {
     // Found "$" in double quote.
     InDoubleQuoteVarStart = 42,
     InDoubleQuoteVar = 43,
+    // Found "${" in double quote.
+    InDoubleQuoteBrace = 44,
     // Found "\r".
     CarriageReturn = 100,
 }
 
 // Token is a single element that make up a line.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 struct Token {
-    words: Vec<Word>,
+    words:  Vec<Word>,
+    /// The length of the unquoted literal prefix, where names and assignments are recognized.
+    bare:   usize,
+    /// Whether the prefix has ended, e.g., by a quote.
+    sealed: bool,
+    /// The line where the token starts.
+    line:   usize,
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -143,25 +553,106 @@ enum Word {
     Lit(Vec<u8>),
     /// A variable $LIKE_THIS.
     ///
-    /// Not support:
-    /// - bracing: ${LIKE_THIS}
+    /// Not supported:
     /// - nesting: $$LIKE_THIS
-    Var(Vec<u8>),
+    Var(Var),
+    /// A parameter expansion ${LIKE_THIS}, optionally with an operator like ${LIKE_THIS:-default}.
+    Param(Box<Param>),
     /// A word for the *non-escaped* newline delimiter.
     NewLine(u8),
 }
 
+// The prefix and the location are not a part of the words.
+impl PartialEq for Token {
+    fn eq(&self, other: &Self) -> bool {
+        self.words == other.words
+    }
+}
+
+impl Eq for Token {}
+
 impl fmt::Debug for Word {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Lit(v) => f.debug_tuple("Lit").field(&String::from_utf8_lossy(v)).finish(),
-            Var(v) => f.debug_tuple("Var").field(&String::from_utf8_lossy(v)).finish(),
+            Var(var) => f.debug_tuple("Var").field(&String::from_utf8_lossy(&var.name)).finish(),
+            Param(param) => f.debug_tuple("Param").field(param).finish(),
             NewLine(b) => f.debug_tuple("NewLine").field(&(*b as char)).finish(),
         }
     }
 }
 
+/// A variable `$NAME`.
+#[derive(Debug, Clone)]
+struct Var {
+    name: Vec<u8>,
+    /// Where the variable is, reported if it is unset in strict mode.
+    pos:  Pos,
+}
+
+/// A parameter expansion `${NAME}`, or `${NAME<op>word}`.
+#[derive(Clone)]
+struct Param {
+    name: String,
+    op:   Option<Op>,
+    /// Where the expansion is, reported if `${NAME:?message}` fails.
+    mark: Mark,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Op {
+    operator: Operator,
+    /// With a colon, a null value is treated as if the variable is unset.
+    colon:    bool,
+    word:     Vec<Word>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    /// `${NAME:-word}` expands to the word if unset.
+    Default,
+    /// `${NAME:=word}` also assigns the word if unset.
+    Assign,
+    /// `${NAME:?message}` fails with the message if unset.
+    Error,
+    /// `${NAME:+word}` expands to the word if set.
+    Alt,
+}
+
+impl fmt::Debug for Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Param").field("name", &self.name).field("op", &self.op).finish()
+    }
+}
+
+// The location is not a part of the variable.
+impl PartialEq for Var {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Var {}
+
+// The location is not a part of the expansion.
+impl PartialEq for Param {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.op == other.op
+    }
+}
+
+impl Eq for Param {}
+
+/// Finds the closing brace of `${...}`, skipping quotes, escapes and nested expansions.
+#[derive(Debug, Clone, Default)]
+struct BraceScan {
+    depth:   usize,
+    quote:   Option<u8>,
+    escaped: bool,
+    dollar:  bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum Action {
     // Do not consume an input byte, epsilon transion.
     LeftOver,
@@ -169,6 +660,8 @@ enum Action {
     NextByte,
     // Token has completed.
     Complete,
+    // The input is malformed.
+    Fail(ParseErrorKind),
 }
 
 #[inline]
@@ -186,16 +679,26 @@ fn complete(state: State) -> (State, Action) {
     (state, Action::Complete)
 }
 
+#[inline]
+fn fail(kind: ParseErrorKind) -> (State, Action) {
+    (State::FindNextNonAsciiWhiteSpace, Action::Fail(kind))
+}
+
 impl<I> Iterator for Lexer<I>
 where
     I: Iterator<Item = u8>,
 {
-    type Item = Token;
-    fn next(&mut self) -> Option<Token> {
-        let mut b = self.bytes.next()?;
+    type Item = Result<Token, ParseError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let Some((mut b, mut pos)) = self.bump() else {
+            return self.end().map(Err);
+        };
         let mut token = Token::new();
         loop {
-            match self.next_action(&mut token, b) {
+            match self.next_action(&mut token, b, pos) {
                 Action::LeftOver => {
                     // Do not call bytes.next().
                     continue;
@@ -206,15 +709,25 @@ where
                 Action::Complete => {
                     break;
                 }
+                Action::Fail(kind) => {
+                    self.failed = true;
+                    return Some(Err(self.mark.error(kind)));
+                }
             }
-            if let Some(next) = self.bytes.next() {
-                b = next;
+            if let Some(next) = self.bump() {
+                (b, pos) = next;
+            } else if let Some(err) = self.end() {
+                return Some(Err(err));
             } else {
                 break;
             }
         }
 
-        if token.words.is_empty() { None } else { Some(token) }
+        if token.words.is_empty() {
+            return None;
+        }
+        self.pending = !matches!(token.words.last(), Some(NewLine(_)));
+        Some(Ok(token))
     }
 }
 
@@ -223,7 +736,18 @@ impl<I> Lexer<I> {
     where
         T: IntoIterator<Item = u8, IntoIter = I>,
     {
-        Lexer { bytes: bytes.into_iter(), state: State::FindNextNonAsciiWhiteSpace }
+        let start = Pos { offset: 0, line: 1, column: 1 };
+        Lexer {
+            bytes:   bytes.into_iter(),
+            state:   State::FindNextNonAsciiWhiteSpace,
+            next:    start,
+            quote:   Mark::new(start),
+            mark:    Mark::new(start),
+            scan:    BraceScan::default(),
+            braced:  Vec::new(),
+            pending: false,
+            failed:  false,
+        }
     }
 }
 
@@ -232,12 +756,94 @@ where
     I: Iterator<Item = u8>,
 {
     #[inline]
-    fn next_action(&mut self, token: &mut Token, b: u8) -> Action {
+    fn next_action(&mut self, token: &mut Token, b: u8, pos: Pos) -> Action {
+        use State::*;
         let (state, action) = self.transition(token, b);
+        if state != self.state {
+            match state {
+                NoQuote if self.state == FindNextNonAsciiWhiteSpace && token.words.is_empty() => {
+                    token.line = pos.line;
+                }
+                InSingleQuote | InDoubleQuote if matches!(self.state, NoQuote | NoQuoteVar) => {
+                    self.quote = Mark::start(pos, b);
+                }
+                NoQuoteEscape | NoQuoteVarStart | InDoubleQuoteVarStart => {
+                    self.mark = Mark::start(pos, b);
+                }
+                _ => (),
+            }
+        }
         self.state = state;
         action
     }
 
+    fn start_brace(&mut self, state: State) -> (State, Action) {
+        self.scan = BraceScan::default();
+        self.braced.clear();
+        nextbyte(state)
+    }
+
+    /// Collects the contents of `${...}`, and parses them at the closing brace.
+    fn brace(&mut self, token: &mut Token, b: u8, state: State, then: State) -> (State, Action) {
+        if !self.scan.feed(b) {
+            self.braced.push(b);
+            return nextbyte(state);
+        }
+        match Param::parse(&self.braced, &self.mark) {
+            Ok(param) => {
+                token.words.push(Param(Box::new(param)));
+                nextbyte(then)
+            }
+            Err(kind) => fail(kind),
+        }
+    }
+
+    /// Consumes the next byte, and returns it with its position.
+    fn bump(&mut self) -> Option<(u8, Pos)> {
+        let b = self.bytes.next()?;
+        let mut pos = self.next;
+        self.next.offset += 1;
+        if b == b'\n' {
+            self.next.line += 1;
+            self.next.column = 1;
+        } else if is_utf8_continuation(b) {
+            // Belongs to the same character as the previous byte.
+            pos.column -= 1;
+        } else {
+            self.next.column += 1;
+        }
+        self.quote.push(b);
+        self.mark.push(b);
+        Some((b, pos))
+    }
+
+    /// Checks the state at the end of the input.
+    fn end(&mut self) -> Option<ParseError> {
+        use State::*;
+        let err = match self.state {
+            InSingleQuote
+            | InDoubleQuote
+            | InDoubleQuoteEscape
+            | InDoubleQuoteVarStart
+            | InDoubleQuoteVar => {
+                let quote = self.quote.snippet[0] as char;
+                self.quote.error(ParseErrorKind::UnterminatedQuote(quote))
+            }
+            NoQuoteEscape => self.mark.error(ParseErrorKind::DanglingEscape),
+            NoQuoteVarStart => self.mark.error(ParseErrorKind::BadVariable),
+            NoQuoteBrace | InDoubleQuoteBrace => self.mark.error(ParseErrorKind::UnterminatedBrace),
+            FindNextNonAsciiWhiteSpace
+            | Comment
+            | NoQuote
+            | NoQuoteEscapeCarriageReturn
+            | NoQuoteVar
+            | CarriageReturn => return None,
+        };
+        self.failed = true;
+        self.state = FindNextNonAsciiWhiteSpace;
+        Some(err)
+    }
+
     // It may be possible to convert nfa to dfa and create a static transition table. To implement
     // this, I think it seems necessary to move token mutations to somewhere.
     //
@@ -247,9 +853,22 @@ where
         use State::*;
         match self.state {
             FindNextNonAsciiWhiteSpace => match b {
+                // The token is not empty after an escaped line break.
+                b'\n' if self.pending || !token.words.is_empty() => {
+                    token.break_line(b);
+                    complete(FindNextNonAsciiWhiteSpace)
+                }
+                b if b.is_ascii_whitespace() && !token.words.is_empty() => {
+                    complete(FindNextNonAsciiWhiteSpace)
+                }
                 b if b.is_ascii_whitespace() => nextbyte(FindNextNonAsciiWhiteSpace),
+                b'#' if token.words.is_empty() => nextbyte(Comment),
                 _ => leftover(NoQuote),
             },
+            Comment => match b {
+                b'\n' => leftover(FindNextNonAsciiWhiteSpace),
+                _ => nextbyte(Comment),
+            },
             NoQuote => match b {
                 b'\r' => nextbyte(CarriageReturn),
                 b'\n' => {
@@ -262,26 +881,40 @@ where
                 b'"' => nextbyte(InDoubleQuote),
                 b'$' => nextbyte(NoQuoteVarStart),
                 _ => {
-                    token.lit().push(b);
+                    token.push_bare(b);
                     nextbyte(NoQuote)
                 }
             },
             NoQuoteEscape => match b {
-                b'\r' => nextbyte(FindNextNonAsciiWhiteSpace),
+                b'\r' => nextbyte(NoQuoteEscapeCarriageReturn),
                 b'\n' => nextbyte(FindNextNonAsciiWhiteSpace),
                 _ => {
                     token.lit().push(b);
                     nextbyte(NoQuote)
                 }
             },
+            NoQuoteEscapeCarriageReturn => match b {
+                b'\n' => nextbyte(FindNextNonAsciiWhiteSpace),
+                _ => {
+                    token.lit().push(b'\r');
+                    leftover(NoQuote)
+                }
+            },
             NoQuoteVarStart => match b {
-                b if b.is_ascii_alphanumeric() || b == b'_' => leftover(NoQuoteVar),
-                _ => unimplemented!("Expected a variable name after $"),
+                b'{' => self.start_brace(NoQuoteBrace),
+                b if is_name(b) => {
+                    token.words.push(Var(Var { name: vec![], pos: self.mark.pos }));
+                    leftover(NoQuoteVar)
+                }
+                _ => fail(ParseErrorKind::BadVariable),
             },
+            NoQuoteBrace => self.brace(token, b, NoQuoteBrace, NoQuote),
             NoQuoteVar => match b {
                 b'\'' => nextbyte(InSingleQuote),
                 b'"' => nextbyte(InDoubleQuote),
                 b'\\' => nextbyte(NoQuote),
+                b'$' => nextbyte(NoQuoteVarStart),
+                b'\r' | b'\n' => leftover(NoQuote),
                 b if b.is_ascii_whitespace() => complete(FindNextNonAsciiWhiteSpace),
                 b if b.is_ascii_alphanumeric() || b == b'_' => {
                     token.var().push(b);
@@ -293,14 +926,20 @@ where
                 }
             },
             InSingleQuote => match b {
-                b'\'' => nextbyte(NoQuote),
+                b'\'' => {
+                    token.close_quote();
+                    nextbyte(NoQuote)
+                }
                 _ => {
                     token.lit().push(b);
                     nextbyte(InSingleQuote)
                 }
             },
             InDoubleQuote => match b {
-                b'"' => nextbyte(NoQuote),
+                b'"' => {
+                    token.close_quote();
+                    nextbyte(NoQuote)
+                }
                 b'\\' => nextbyte(InDoubleQuoteEscape),
                 b'$' => nextbyte(InDoubleQuoteVarStart),
                 _ => {
@@ -321,9 +960,14 @@ where
                 }
             },
             InDoubleQuoteVarStart => match b {
-                b if b.is_ascii_alphanumeric() || b == b'_' => leftover(InDoubleQuoteVar),
-                _ => unimplemented!("Expected a variable name after $"),
+                b'{' => self.start_brace(InDoubleQuoteBrace),
+                b if is_name(b) => {
+                    token.words.push(Var(Var { name: vec![], pos: self.mark.pos }));
+                    leftover(InDoubleQuoteVar)
+                }
+                _ => fail(ParseErrorKind::BadVariable),
             },
+            InDoubleQuoteBrace => self.brace(token, b, InDoubleQuoteBrace, InDoubleQuote),
             InDoubleQuoteVar => match b {
                 b'"' => nextbyte(NoQuote),
                 b'$' => nextbyte(InDoubleQuoteVarStart),
@@ -352,9 +996,204 @@ where
     }
 }
 
+impl Mark {
+    fn new(pos: Pos) -> Self {
+        Mark { pos, snippet: Vec::new(), open: false }
+    }
+
+    fn start(pos: Pos, b: u8) -> Self {
+        Mark { pos, snippet: vec![b], open: true }
+    }
+
+    /// Appends the byte to the snippet, until the end of the line.
+    fn push(&mut self, b: u8) {
+        if !self.open {
+            return;
+        }
+        // Not to split a character when truncating.
+        let full = self.snippet.len() >= SNIPPET_LEN && !is_utf8_continuation(b);
+        if b == b'\n' || b == b'\r' || full {
+            self.open = false;
+        } else {
+            self.snippet.push(b);
+        }
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        let Pos { offset, line, column } = self.pos;
+        let snippet = String::from_utf8_lossy(&self.snippet).into_owned();
+        ParseError { kind, line, column, offset, snippet }
+    }
+}
+
+impl Var {
+    fn unbound(&self) -> ParseError {
+        let Pos { offset, line, column } = self.pos;
+        let name = String::from_utf8_lossy(&self.name).into_owned();
+        let snippet = format!("${name}");
+        ParseError { kind: ParseErrorKind::Unbound(name), line, column, offset, snippet }
+    }
+}
+
+impl Param {
+    /// Parses the contents of `${...}`.
+    fn parse(bytes: &[u8], mark: &Mark) -> Result<Param, ParseErrorKind> {
+        let len = bytes.iter().take_while(|&&b| is_name(b)).count();
+        if len == 0 {
+            return Err(ParseErrorKind::BadSubstitution);
+        }
+        let name = String::from_utf8_lossy(&bytes[..len]).into_owned();
+        let (colon, rest) = match &bytes[len..] {
+            [b':', rest @ ..] => (true, rest),
+            rest => (false, rest),
+        };
+        let op = match rest {
+            [] if !colon => None,
+            [operator, word @ ..] => {
+                let operator = match operator {
+                    b'-' => Operator::Default,
+                    b'=' => Operator::Assign,
+                    b'?' => Operator::Error,
+                    b'+' => Operator::Alt,
+                    _ => return Err(ParseErrorKind::BadSubstitution),
+                };
+                Some(Op { operator, colon, word: parse_word(word, mark)? })
+            }
+            [] => return Err(ParseErrorKind::BadSubstitution),
+        };
+        Ok(Param { name, op, mark: mark.clone() })
+    }
+
+    fn expand<R: Resolver>(&self, scope: &mut Scope<'_, R>) -> Result<String, ParseError> {
+        let value = scope.get(&self.name);
+        let Some(op) = &self.op else {
+            return match value {
+                None if scope.strict => {
+                    Err(self.mark.error(ParseErrorKind::Unbound(self.name.clone())))
+                }
+                value => Ok(value.unwrap_or_default()),
+            };
+        };
+        let unset = value.as_ref().is_none_or(|value| op.colon && value.is_empty());
+        match (op.operator, unset) {
+            (Operator::Default, true) => scope.expand(&op.word),
+            (Operator::Assign, true) => {
+                let value = scope.expand(&op.word)?;
+                scope.assigned.insert(self.name.clone(), value.clone());
+                Ok(value)
+            }
+            (Operator::Error, true) => {
+                let message = scope.expand(&op.word)?;
+                Err(self.mark.error(ParseErrorKind::Unset { name: self.name.clone(), message }))
+            }
+            (Operator::Alt, true) => Ok(String::new()),
+            (Operator::Alt, false) => scope.expand(&op.word),
+            (_, false) => Ok(value.unwrap_or_default()),
+        }
+    }
+}
+
+/// Parses the word of an operator, from which quotes are removed.
+fn parse_word(bytes: &[u8], mark: &Mark) -> Result<Vec<Word>, ParseErrorKind> {
+    let mut word = Token::new();
+    let mut double = false;
+    let mut i = 0;
+    while let Some(&b) = bytes.get(i) {
+        i += 1;
+        match b {
+            b'\\' => {
+                if let Some(&b) = bytes.get(i) {
+                    word.lit().push(b);
+                    i += 1;
+                }
+            }
+            b'\'' if !double => {
+                let len = bytes[i..].iter().position(|&b| b == b'\'').unwrap_or(bytes.len() - i);
+                word.lit().extend_from_slice(&bytes[i..i + len]);
+                i += len + 1;
+            }
+            b'"' => double = !double,
+            b'$' => match bytes.get(i) {
+                Some(b'{') => {
+                    let braced = &bytes[i + 1..];
+                    let mut scan = BraceScan::default();
+                    let len = braced
+                        .iter()
+                        .position(|&b| scan.feed(b))
+                        .ok_or(ParseErrorKind::UnterminatedBrace)?;
+                    word.words.push(Param(Box::new(Param::parse(&braced[..len], mark)?)));
+                    i += len + 2;
+                }
+                Some(&b) if is_name(b) => {
+                    let len = bytes[i..].iter().take_while(|&&b| is_name(b)).count();
+                    let name = bytes[i..i + len].to_vec();
+                    word.words.push(Var(Var { name, pos: mark.pos }));
+                    i += len;
+                }
+                _ => return Err(ParseErrorKind::BadVariable),
+            },
+            _ => word.lit().push(b),
+        }
+    }
+    Ok(word.words)
+}
+
+impl BraceScan {
+    /// Feeds the byte following `${`, and returns true if it is the closing brace.
+    fn feed(&mut self, b: u8) -> bool {
+        let dollar = std::mem::take(&mut self.dollar);
+        if std::mem::take(&mut self.escaped) {
+            return false;
+        }
+        match (self.quote, b) {
+            (Some(b'\''), b'\'') => self.quote = None,
+            (Some(b'\''), _) => (),
+            (_, b'\\') => self.escaped = true,
+            (Some(_), b'"') => self.quote = None,
+            (Some(_), _) => (),
+            (None, b'\'' | b'"') => self.quote = Some(b),
+            (None, b'$') => self.dollar = true,
+            (None, b'{') if dollar => self.depth += 1,
+            (None, b'}') if self.depth == 0 => return true,
+            (None, b'}') => self.depth -= 1,
+            (None, _) => (),
+        }
+        false
+    }
+}
+
+fn is_name(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+fn is_utf8_continuation(b: u8) -> bool {
+    b & 0xC0 == 0x80
+}
+
 impl Token {
     fn new() -> Self {
-        Token { words: Vec::new() }
+        Token { words: Vec::new(), bare: 0, sealed: false, line: 0 }
+    }
+
+    /// Pushes an unquoted literal byte, which extends the prefix unless anything else precedes.
+    fn push_bare(&mut self, b: u8) {
+        let extends = !self.sealed
+            && match &self.words[..] {
+                [] => true,
+                [Lit(lit)] => lit.len() == self.bare,
+                _ => false,
+            };
+        self.sealed |= !extends;
+        self.bare += usize::from(extends);
+        self.lit().push(b);
+    }
+
+    /// Quotes make a token even if they are empty, e.g., `''`.
+    fn close_quote(&mut self) {
+        self.sealed = true;
+        if self.words.is_empty() {
+            self.words.push(Lit(Vec::new()));
+        }
     }
 
     fn lit(&mut self) -> &mut Vec<u8> {
@@ -368,7 +1207,7 @@ impl Token {
     fn ensure_last_is_lit(&mut self) {
         if let Some(last) = self.words.last_mut() {
             match last {
-                Var(_) => {
+                Var(_) | Param(_) => {
                     self.words.push(Lit(vec![]));
                 }
                 Lit(_) => (),
@@ -380,25 +1219,10 @@ impl Token {
     }
 
     fn var(&mut self) -> &mut Vec<u8> {
-        self.ensure_last_is_var();
-        let Var(vec) = self.words.last_mut().unwrap() else {
-            unreachable!("ensure_last_is_var does not work as expected");
+        let Some(Var(var)) = self.words.last_mut() else {
+            unreachable!("a variable is pushed when $ is found");
         };
-        vec
-    }
-
-    fn ensure_last_is_var(&mut self) {
-        if let Some(last) = self.words.last_mut() {
-            match last {
-                Lit(_) => {
-                    self.words.push(Var(vec![]));
-                }
-                Var(_) => (),
-                NewLine(_) => unreachable!("pushing a byte onto NewLine"),
-            }
-        } else {
-            self.words.push(Var(vec![]));
-        }
+        &mut var.name
     }
 
     fn break_line(&mut self, b: u8) {
@@ -413,6 +1237,9 @@ mod tests {
     use super::Lexer;
     use crate::Word::*;
     use crate::{
+        ParseError,
+        ParseErrorKind,
+        Pos,
         Token,
         Word,
     };
@@ -424,7 +1251,14 @@ mod tests {
 
     fn tokens(source: &str) -> Vec<Token> {
         let mut lex = Lexer::new(source.bytes());
-        iter::from_fn(|| lex.next()).collect()
+        iter::from_fn(|| lex.next()).collect::<Result<_, _>>().unwrap()
+    }
+
+    fn error(source: &str) -> ParseError {
+        let mut lex = Lexer::new(source.bytes());
+        let err = iter::from_fn(|| lex.next()).find_map(Result::err).expect("no error");
+        assert_eq!(lex.next(), None, "stops after an error");
+        err
     }
 
     #[test]
@@ -439,7 +1273,8 @@ mod tests {
             Token {
                 words: vec![
                     $($word),+
-                ]
+                ],
+                ..Token::new()
             }
         };
     }
@@ -449,12 +1284,12 @@ mod tests {
     }
 
     fn var(bytes: &[u8]) -> Word {
-        Var(bytes.to_vec())
+        let pos = Pos { offset: 0, line: 1, column: 1 };
+        Var(crate::Var { name: bytes.to_vec(), pos })
     }
 
     #[test]
     fn no_tokens() {
-        assert_eq!(tokens("\\"), []);
         assert_eq!(tokens("\\\n"), []);
         assert_eq!(tokens("\\\n "), []);
         assert_eq!(tokens("\n \n"), []);
@@ -543,6 +1378,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn line_breaks_after_whitespaces() {
+        assert_eq!(
+            tokens("a \nb # c\n# d\n\ne\\\r\nf"),
+            [
+                Token![lit(b"a")],
+                Token![NewLine(b'\n')],
+                Token![lit(b"b")],
+                Token![NewLine(b'\n')],
+                Token![lit(b"ef")],
+            ]
+        );
+    }
+
     #[test]
     fn escape_ascii_whitespace() {
         assert_eq!(tokens("\\ "), [Token![lit(b" ")]]);
@@ -590,9 +1439,33 @@ mod tests {
         assert_eq!(tokens("\"$VAR\\A\""), [Token![var(b"VAR"), lit(b"\\A")]]);
         assert_eq!(tokens("$VAR\"\""), [Token![var(b"VAR")]]);
         assert_eq!(tokens("$VAR\"ABC\""), [Token![var(b"VAR"), lit(b"ABC")]]);
+        assert_eq!(tokens("$A$B"), [Token![var(b"A"), var(b"B")]]);
+        assert_eq!(tokens("\"$A$B\""), [Token![var(b"A"), var(b"B")]]);
+        assert_eq!(tokens("$A\nB"), [Token![var(b"A"), NewLine(b'\n')], Token![lit(b"B")]]);
         assert_eq!(
             tokens("e\"$VAR\"o hello"),
             [Token![lit(b"e"), var(b"VAR"), lit(b"o")], Token![lit(b"hello")]]
         );
     }
+
+    #[test]
+    fn errors() {
+        let check = |source: &str, kind, (line, column, offset), snippet: &str| {
+            let err = error(source);
+            assert_eq!(err.kind, kind, "{source:?}");
+            assert_eq!((err.line, err.column, err.offset), (line, column, offset), "{source:?}");
+            assert_eq!(err.snippet, snippet, "{source:?}");
+        };
+        check("\\", ParseErrorKind::DanglingEscape, (1, 1, 0), "\\");
+        check("a \\", ParseErrorKind::DanglingEscape, (1, 3, 2), "\\");
+        check("$-", ParseErrorKind::BadVariable, (1, 1, 0), "$-");
+        check("a\n \"$\"", ParseErrorKind::BadVariable, (2, 3, 4), "$\"");
+        check("え $", ParseErrorKind::BadVariable, (1, 3, 4), "$");
+        check("'a b\nc", ParseErrorKind::UnterminatedQuote('\''), (1, 1, 0), "'a b");
+        check("a\n\"$A", ParseErrorKind::UnterminatedQuote('"'), (2, 1, 2), "\"$A");
+        check("x 'a'\"\\", ParseErrorKind::UnterminatedQuote('"'), (1, 6, 5), "\"\\");
+
+        let long = format!("\"{}", "え".repeat(20));
+        assert_eq!(error(&long).snippet, format!("\"{}", "え".repeat(13)));
+    }
 }
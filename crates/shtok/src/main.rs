@@ -3,10 +3,8 @@
 use std::io;
 
 fn main() -> io::Result<()> {
-    if let Ok(entries) = shtok::parse(io::stdin(), None) {
-        for e in entries {
-            println!("{e:?}");
-        }
+    for e in shtok::parse(io::stdin(), None)? {
+        println!("{e:?}");
     }
     Ok(())
 }
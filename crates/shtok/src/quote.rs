@@ -0,0 +1,128 @@
+//! Quoting arguments, the inverse of tokenizing.
+
+use std::borrow::Cow;
+
+/// Quotes the argument, so that [`Tokens`](crate::Tokens) reads it back as a single token.
+///
+/// The argument is left bare if it has no special characters, and [`parse`](crate::parse) would
+/// not read it as an entry name or an assignment, e.g., `web:` or `PORT=8080`. Otherwise it is
+/// single-quoted, or double-quoted if it contains single quotes. Single quotes in an argument that
+/// also has double quotes or backslashes are escaped between single-quoted parts, e.g.,
+/// `'it'\''s'`.
+///
+/// ```
+/// assert_eq!(shtok::quote("gunicorn"), "gunicorn");
+/// assert_eq!(shtok::quote("main:app"), "'main:app'");
+/// assert_eq!(shtok::quote("echo $PORT"), "'echo $PORT'");
+/// assert_eq!(shtok::quote("don't $STOP"), r#""don't \$STOP""#);
+/// assert_eq!(shtok::quote(""), "''");
+/// ```
+pub fn quote(arg: &str) -> Cow<'_, str> {
+    match quote_bytes(arg.as_bytes()) {
+        Cow::Borrowed(_) => Cow::Borrowed(arg),
+        // Only ASCII quotes and escapes are added to the argument.
+        Cow::Owned(quoted) => Cow::Owned(String::from_utf8(quoted).unwrap()),
+    }
+}
+
+/// Quotes the argument as [`quote`] does, which may not be UTF-8, e.g., a path.
+///
+/// [`Tokens::next_bytes`](crate::Tokens::next_bytes) reads it back as it is, while
+/// [`Tokens`](crate::Tokens) replaces invalid UTF-8 with U+FFFD.
+///
+/// ```
+/// assert_eq!(shtok::quote_bytes(b"\xff.log"), &b"\xff.log"[..]);
+/// assert_eq!(shtok::quote_bytes(b"a \xff"), &b"'a \xff'"[..]);
+/// ```
+pub fn quote_bytes(arg: &[u8]) -> Cow<'_, [u8]> {
+    if !arg.is_empty() && arg[0] != b'#' && !arg.iter().any(|&b| is_special(b)) && !is_prefixed(arg)
+    {
+        return Cow::Borrowed(arg);
+    }
+    if !arg.contains(&b'\'') {
+        return Cow::Owned([b"'", arg, b"'"].concat());
+    }
+    // Backslashes are kept as is in double quotes, except for `\$`, and cannot escape `"`.
+    if !arg.iter().any(|&b| matches!(b, b'"' | b'\\')) {
+        let mut quoted = vec![b'"'];
+        for &b in arg {
+            if b == b'$' {
+                quoted.push(b'\\');
+            }
+            quoted.push(b);
+        }
+        quoted.push(b'"');
+        return Cow::Owned(quoted);
+    }
+    // Single-quotes the rest, and escapes single quotes outside of them, e.g., `'it'\''s'`.
+    let quoted = arg
+        .split(|&b| b == b'\'')
+        .map(|part| if part.is_empty() { Vec::new() } else { [b"'", part, b"'"].concat() })
+        .collect::<Vec<_>>();
+    Cow::Owned(quoted.join(&b"\\'"[..]))
+}
+
+/// Quotes and joins the arguments into a command line.
+///
+/// ```
+/// # use shtok::Tokens;
+/// let line = shtok::join(["sh", "-c", "echo \"$HOME\""]);
+/// assert_eq!(line, r#"sh -c 'echo "$HOME"'"#);
+/// assert_eq!(
+///     Tokens::new(line.bytes(), None).next().unwrap().unwrap(),
+///     ["sh", "-c", "echo \"$HOME\""]
+/// );
+/// ```
+pub fn join<I>(argv: I) -> String
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let mut line = String::new();
+    for (i, arg) in argv.into_iter().enumerate() {
+        if i > 0 {
+            line.push(' ');
+        }
+        line.push_str(&quote(arg.as_ref()));
+    }
+    line
+}
+
+/// Quotes and joins the arguments into a command line as [`join`] does, which may not be UTF-8.
+///
+/// ```
+/// # use std::ffi::OsStr;
+/// # use std::os::unix::ffi::OsStrExt;
+/// let argv = [OsStr::new("cat"), OsStr::from_bytes(b"\xff log")];
+/// assert_eq!(shtok::join_bytes(argv.map(OsStr::as_bytes)), b"cat '\xff log'");
+/// ```
+pub fn join_bytes<I>(argv: I) -> Vec<u8>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut line = Vec::new();
+    for (i, arg) in argv.into_iter().enumerate() {
+        if i > 0 {
+            line.push(b' ');
+        }
+        line.extend_from_slice(&quote_bytes(arg.as_ref()));
+    }
+    line
+}
+
+/// Whether the argument starts with an entry name or the key of an assignment, which are recognized
+/// only in the unquoted prefixes of the tokens.
+fn is_prefixed(arg: &[u8]) -> bool {
+    let prefixed = |is_key: fn(u8) -> bool, sep: u8| {
+        let len = arg.iter().take_while(|&&b| is_key(b)).count();
+        len > 0 && arg.get(len) == Some(&sep)
+    };
+    prefixed(crate::is_entry_name, b':') || prefixed(crate::is_name, b'=')
+}
+
+/// Whether the byte has to be quoted, i.e., whitespace, control characters, quotes, escapes and
+/// variables.
+fn is_special(b: u8) -> bool {
+    b.is_ascii_whitespace() || b.is_ascii_control() || matches!(b, b'\'' | b'"' | b'\\' | b'$')
+}
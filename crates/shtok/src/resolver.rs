@@ -0,0 +1,93 @@
+//! Looking up the values of variables.
+
+use std::collections::HashMap;
+use std::env;
+use std::hash::BuildHasher;
+
+/// Resolves variables referenced by `$NAME` or `${NAME}`.
+///
+/// ```
+/// # use std::collections::HashMap;
+/// # use shtok::{Env, Resolver, Tokens};
+/// let vars = HashMap::from([("PORT".to_owned(), "8080".to_owned())]);
+/// let mut tokens = Tokens::with_resolver("-b :$PORT".bytes(), vars.or(Env));
+/// assert_eq!(tokens.next().unwrap().unwrap(), ["-b", ":8080"]);
+/// ```
+pub trait Resolver {
+    /// Returns the value of the variable, or None if it is unset.
+    fn resolve(&self, name: &str) -> Option<String>;
+
+    /// Falls back to the other resolver for the variables unset in this one.
+    fn or<R>(self, fallback: R) -> Chain<Self, R>
+    where
+        Self: Sized,
+        R: Resolver,
+    {
+        Chain(self, fallback)
+    }
+}
+
+/// Resolves variables from the environment of the current process.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Env;
+
+/// Resolves variables from the first resolver, and then from the second one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Chain<A, B>(pub A, pub B);
+
+/// Resolves variables by the closure. See [`from_fn`].
+#[derive(Clone, Copy)]
+pub struct FromFn<F>(F);
+
+/// Creates a resolver from the closure.
+///
+/// ```
+/// # use shtok::{Resolver, from_fn};
+/// let upper = from_fn(|name: &str| Some(name.to_uppercase()));
+/// assert_eq!(upper.resolve("port").as_deref(), Some("PORT"));
+/// ```
+pub fn from_fn<F>(f: F) -> FromFn<F>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    FromFn(f)
+}
+
+impl<S: BuildHasher> Resolver for HashMap<String, String, S> {
+    fn resolve(&self, name: &str) -> Option<String> {
+        self.get(name).cloned()
+    }
+}
+
+impl Resolver for Env {
+    fn resolve(&self, name: &str) -> Option<String> {
+        env::var(name).ok()
+    }
+}
+
+impl<A: Resolver, B: Resolver> Resolver for Chain<A, B> {
+    fn resolve(&self, name: &str) -> Option<String> {
+        self.0.resolve(name).or_else(|| self.1.resolve(name))
+    }
+}
+
+impl<F> Resolver for FromFn<F>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    fn resolve(&self, name: &str) -> Option<String> {
+        (self.0)(name)
+    }
+}
+
+impl<R: Resolver + ?Sized> Resolver for &R {
+    fn resolve(&self, name: &str) -> Option<String> {
+        (**self).resolve(name)
+    }
+}
+
+impl<R: Resolver + ?Sized> Resolver for Box<R> {
+    fn resolve(&self, name: &str) -> Option<String> {
+        (**self).resolve(name)
+    }
+}
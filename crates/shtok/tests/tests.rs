@@ -2,9 +2,21 @@
 use std::collections::HashMap;
 use std::io;
 
+use quickcheck_macros::quickcheck;
 use shtok::{
+    Entry,
+    Env,
+    Error,
+    ParseErrorKind,
+    Resolver,
     Tokens,
+    from_fn,
+    join,
+    join_bytes,
     parse,
+    quote,
+    quote_bytes,
+    referenced_vars,
 };
 
 #[test]
@@ -60,14 +72,14 @@ test7 a  b \c \ \
     };
     let entries = parse(io::Cursor::new(rc), envs).expect("reading from a cursor never fails");
 
-    assert_eq!(entries[0].flag, ["test0"]);
-    assert_eq!(entries[1].flag, ["test1", "gunicorn", "-b", ":8080", "main:app"]);
-    assert_eq!(entries[2].flag, ["test2", "-x", "10s", "--env", "ABC=def", "--", "foo", " bar"]);
-    assert_eq!(entries[3].flag, ["test3", "C:\\path", "D:path"]);
-    assert_eq!(entries[4].flag, ["test4", "10m\\n", "--", "foo", "a=1"]);
-    assert_eq!(entries[5].flag, ["test5", "a", "b", "  c"]);
-    assert_eq!(entries[6].flag, ["test6", "a", "b", "c", "d", "e"]);
-    assert_eq!(entries[7].flag, ["test7", "a", "b", "c", " d", "e"]);
+    assert_eq!(entries[0].argv, ["test0"]);
+    assert_eq!(entries[1].argv, ["test1", "gunicorn", "-b", ":8080", "main:app"]);
+    assert_eq!(entries[2].argv, ["test2", "-x", "10s", "--env", "ABC=def", "--", "foo", " bar"]);
+    assert_eq!(entries[3].argv, ["test3", "C:\\path", "D:path"]);
+    assert_eq!(entries[4].argv, ["test4", "10m\\n", "--", "foo", "a=1"]);
+    assert_eq!(entries[5].argv, ["test5", "a", "b", "  c"]);
+    assert_eq!(entries[6].argv, ["test6", "a", "b", "c", "d", "e"]);
+    assert_eq!(entries[7].argv, ["test7", "a", "b", "c", " d", "e"]);
 }
 
 fn tokens_opt(bytes: impl IntoIterator<Item = u8>) -> Option<Vec<String>> {
@@ -75,11 +87,12 @@ fn tokens_opt(bytes: impl IntoIterator<Item = u8>) -> Option<Vec<String>> {
         let mut envs = HashMap::new();
         envs.insert("PORT".to_owned(), "8080".to_owned());
         envs.insert("TEST".to_owned(), "ch".to_owned());
+        envs.insert("EMPTY".to_owned(), "".to_owned());
         Some(envs)
     };
 
     let mut t = Tokens::new(bytes, envs);
-    let tokens = t.next();
+    let tokens = t.next().map(Result::unwrap);
     // Check no more tokens.
     assert_eq!(t.next(), None);
     tokens
@@ -158,7 +171,22 @@ fn quote_in_another_quote() {
 
 #[test]
 fn no_matching_quote() {
-    assert_eq!(tokens("foo\"bar".bytes()), ["foobar"]);
+    let mut t = Tokens::new("foo\"bar".bytes(), None);
+    let err = t.next().unwrap().unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::UnterminatedQuote('"'));
+    assert_eq!((err.line, err.column, err.offset), (1, 4, 3));
+    assert_eq!(err.snippet, "\"bar");
+    assert_eq!(t.next(), None);
+}
+
+#[test]
+fn parse_errors() {
+    let rc = "web server -p $PORT\nworker run \"$\" --queue default\n";
+    let Err(Error::Parse(err)) = parse(io::Cursor::new(rc), None) else {
+        panic!("expected a parse error");
+    };
+    assert_eq!(err.kind, ParseErrorKind::BadVariable);
+    assert_eq!(err.to_string(), "expected a variable name after $ at line 2, column 13: $\"");
 }
 
 #[test]
@@ -174,3 +202,248 @@ fn expand_envs() {
     assert_eq!(tokens("$TEST\"cc\"".bytes()), ["chcc"]);
     assert_eq!(tokens("e\"$TEST\"o hello".bytes()), ["echo", "hello"]);
 }
+
+#[test]
+fn expand_params() {
+    assert_eq!(tokens("${TEST}x".bytes()), ["chx"]);
+    assert_eq!(tokens("\"${TEST}/bin\"".bytes()), ["ch/bin"]);
+    assert_eq!(tokens("$TEST${PORT} \"$TEST$PORT\"".bytes()), ["ch8080", "ch8080"]);
+
+    assert_eq!(tokens("${NOPE:-1} ${PORT:-1} ${EMPTY:-1}".bytes()), ["1", "8080", "1"]);
+    assert_eq!(tokens("${NOPE-1} ${PORT-1} ${EMPTY-1}".bytes()), ["1", "8080", ""]);
+    assert_eq!(tokens("${NOPE:+1} ${PORT:+1} ${EMPTY:+1}".bytes()), ["", "1", ""]);
+    assert_eq!(tokens("${NOPE+1} ${PORT+1} ${EMPTY+1}".bytes()), ["", "1", "1"]);
+    assert_eq!(tokens("${TEST:?unset}".bytes()), ["ch"]);
+
+    // The word is expanded only if used.
+    assert_eq!(tokens("${NOPE:-${TEST}-$PORT}".bytes()), ["ch-8080"]);
+    assert_eq!(tokens("${TEST:-${NOPE:?unset}}".bytes()), ["ch"]);
+    assert_eq!(tokens("${NOPE:-\"a  b\"} \"${NOPE:-a b}\"".bytes()), ["a  b", "a b"]);
+    assert_eq!(tokens("${NOPE:-'}'} ${NOPE:-\\}}".bytes()), ["}", "}"]);
+}
+
+#[test]
+fn assign_params() {
+    let mut t = Tokens::new("${NOPE:=1} $NOPE\n${NOPE:=2}".bytes(), None);
+    assert_eq!(t.next().unwrap().unwrap(), ["1", "1"]);
+    assert_eq!(t.next().unwrap().unwrap(), ["1"]);
+    assert_eq!(t.next(), None);
+}
+
+#[test]
+fn param_errors() {
+    let error = |source: &str| {
+        let mut t = Tokens::new(source.bytes(), None);
+        let err = t.next().unwrap().unwrap_err();
+        assert_eq!(t.next(), None);
+        err
+    };
+
+    let err = error("a \"${NOPE:?must be set}\" b\nc");
+    assert_eq!(
+        err.kind,
+        ParseErrorKind::Unset { name: "NOPE".to_owned(), message: "must be set".to_owned() }
+    );
+    assert_eq!(err.to_string(), "NOPE: must be set at line 1, column 4: ${NOPE:?must be set}");
+    assert_eq!(
+        error("${NOPE:?}").to_string(),
+        "NOPE: parameter null or not set at line 1, column 1: ${NOPE:?}"
+    );
+
+    assert_eq!(error("${}").kind, ParseErrorKind::BadSubstitution);
+    assert_eq!(error("${A%x}").kind, ParseErrorKind::BadSubstitution);
+    assert_eq!(error("${A:}").kind, ParseErrorKind::BadSubstitution);
+    assert_eq!(error("${A:-$}").kind, ParseErrorKind::BadVariable);
+    assert_eq!(error("${A:-'}").kind, ParseErrorKind::UnterminatedBrace);
+    assert_eq!(error("x ${A").kind, ParseErrorKind::UnterminatedBrace);
+}
+
+#[test]
+fn resolvers() {
+    let line = |source: &str, resolver: &dyn Resolver| {
+        Tokens::with_resolver(source.bytes(), resolver).next().unwrap().unwrap()
+    };
+    let vars = HashMap::from([("PORT".to_owned(), "8080".to_owned())]);
+    let path = std::env::var("PATH").unwrap();
+
+    assert_eq!(line("$PORT $PATH", &vars), ["8080", ""]);
+    assert_eq!(line("$PORT $PATH", &Env), ["", path.as_str()]);
+    assert_eq!(line("$PORT $PATH", &(&vars).or(Env)), ["8080", path.as_str()]);
+
+    let vars = from_fn(|name: &str| name.strip_prefix("X_").map(str::to_lowercase));
+    assert_eq!(line("$X_FOO ${X_BAR:-baz} ${Y:-qux}", &vars), ["foo", "bar", "qux"]);
+}
+
+#[test]
+fn strict_mode() {
+    let mut t =
+        Tokens::new("${PORT:-8080} ${HOST-} ${DEBUG:+1} ${A:=1} $A".bytes(), None).strict(true);
+    assert_eq!(t.next().unwrap().unwrap(), ["8080", "", "", "1", "1"]);
+
+    let mut t = Tokens::new("a\nb \"x${HOST}\"".bytes(), None).strict(true);
+    assert_eq!(t.next().unwrap().unwrap(), ["a"]);
+    let err = t.next().unwrap().unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::Unbound("HOST".to_owned()));
+    assert_eq!((err.line, err.column, err.offset), (2, 5, 6));
+    assert_eq!(t.next(), None);
+
+    let mut t =
+        Tokens::new("$EMPTY".bytes(), Some(HashMap::from([("EMPTY".to_owned(), String::new())])));
+    assert_eq!(t.next().unwrap().unwrap(), [""], "set but null is not an error");
+}
+
+#[test]
+fn list_referenced_vars() {
+    let rc = "web $CMD -b ${HOST:-0.0.0.0}:${PORT:-${DEFAULT_PORT}}\nworker '$QUOTED' \\$ESCAPED \"$CMD\"\n";
+    assert_eq!(referenced_vars(rc.bytes()).unwrap(), ["CMD", "HOST", "PORT", "DEFAULT_PORT"]);
+    assert_eq!(referenced_vars("$-".bytes()).unwrap_err().kind, ParseErrorKind::BadVariable);
+}
+
+#[test]
+fn comments() {
+    let rc = r##"
+# A comment line.
+  # An indented one.
+web a#b "#c" '#d' \#e # f "g
+worker \
+  --queue x # trailing
+"##;
+    let entries = parse(io::Cursor::new(rc), None).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].argv, ["web", "a#b", "#c", "#d", "#e"]);
+    assert_eq!(entries[1].argv, ["worker", "--queue", "x"]);
+}
+
+#[test]
+fn trailing_whitespaces() {
+    let entries = parse(io::Cursor::new("a \t\nb \r\n\n  c\n"), None).unwrap();
+    let argv = entries.into_iter().map(|entry| entry.argv).collect::<Vec<_>>();
+    assert_eq!(argv, [["a"], ["b"], ["c"]]);
+}
+
+#[test]
+fn procfile_entries() {
+    let rc = r##"
+web: PORT=${PORT:-80} DEBUG= gunicorn -b :$PORT main:app
+worker:rake jobs:work
+release_2: "A=1" ./migrate.sh
+A=1 B=2
+clock:
+2X=1 ./clock C:\\path
+
+# Neither quoted nor expanded names and assignments are recognized.
+$NAME sleep
+"web:" A\=1 $ASSIGN B"=2" ./web
+cron: \
+  C=3 ./cron
+"##;
+    let envs = Some(HashMap::from([
+        ("PORT".to_owned(), "8080".to_owned()),
+        ("NAME".to_owned(), "db:".to_owned()),
+        ("ASSIGN".to_owned(), "A=1".to_owned()),
+    ]));
+    let entries = parse(io::Cursor::new(rc), envs).unwrap();
+    let entry = |line: usize, name: Option<&str>, env: &[(&str, &str)], argv: &[&str]| Entry {
+        name: name.map(str::to_owned),
+        env: env.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect(),
+        argv: argv.iter().map(|&arg| arg.to_owned()).collect(),
+        line,
+    };
+    assert_eq!(
+        entries,
+        [
+            entry(
+                2,
+                Some("web"),
+                &[("PORT", "8080"), ("DEBUG", "")],
+                &["gunicorn", "-b", ":8080", "main:app"]
+            ),
+            entry(3, Some("worker"), &[], &["rake", "jobs:work"]),
+            entry(4, Some("release_2"), &[], &["A=1", "./migrate.sh"]),
+            entry(5, None, &[("A", "1"), ("B", "2")], &[]),
+            entry(6, Some("clock"), &[], &[]),
+            entry(7, None, &[], &["2X=1", "./clock", "C:\\path"]),
+            entry(10, None, &[], &["db:", "sleep"]),
+            entry(11, None, &[], &["web:", "A=1", "A=1", "B=2", "./web"]),
+            entry(12, Some("cron"), &[("C", "3")], &["./cron"]),
+        ]
+    );
+}
+
+#[test]
+fn empty_quotes() {
+    let mut tokens = Tokens::new("a '' \"\" b\n''\n".bytes(), None);
+    assert_eq!(tokens.next().unwrap().unwrap(), ["a", "", "", "b"]);
+    assert_eq!(tokens.next().unwrap().unwrap(), [""]);
+    assert_eq!(tokens.next(), None);
+}
+
+#[test]
+fn quote_args() {
+    assert_eq!(quote("gunicorn"), "gunicorn");
+    assert_eq!(quote("C:/path#1"), "'C:/path#1'");
+    assert_eq!(quote("web:x"), "'web:x'");
+    assert_eq!(quote("A=1"), "'A=1'");
+    assert_eq!(quote("--bind=:8080"), "--bind=:8080");
+    assert_eq!(quote("./a:b=c"), "./a:b=c");
+    assert_eq!(quote("#1"), "'#1'");
+    assert_eq!(quote("a b"), "'a b'");
+    assert_eq!(quote("$PORT"), "'$PORT'");
+    assert_eq!(quote("a\nb"), "'a\nb'");
+    assert_eq!(quote("it's $HOME"), r#""it's \$HOME""#);
+    assert_eq!(quote("'"), r#""'""#);
+    assert_eq!(quote(r#"it's "quoted""#), r#"'it'\''s "quoted"'"#);
+    assert_eq!(quote(r"it's C:\path"), r"'it'\''s C:\path'");
+    assert_eq!(join(["echo", "", "a b"]), "echo '' 'a b'");
+    assert_eq!(join(Vec::<String>::new()), "");
+    assert_eq!(quote_bytes(b"\xff"), &b"\xff"[..]);
+    assert_eq!(quote_bytes(b"it's \xff\\"), &b"'it'\\''s \xff\\'"[..]);
+    assert_eq!(join_bytes([&b"A=\xff"[..], b""]), b"'A=\xff' ''");
+
+    let entries = parse(join(["A=1", "web:", "echo"]).as_bytes(), None).unwrap();
+    assert_eq!((entries[0].name.as_ref(), &entries[0].env[..]), (None, &[][..]));
+    assert_eq!(entries[0].argv, ["A=1", "web:", "echo"]);
+}
+
+/// Whether the joined arguments are read back as they were, both as tokens and as an entry.
+fn round_trip(argv: &[String]) -> bool {
+    let line = join(argv);
+    let mut tokens = Tokens::new(line.bytes(), None).strict(true);
+    let tokens = match tokens.next() {
+        None => argv.is_empty(),
+        Some(line) => line.ok().as_deref() == Some(argv) && tokens.next().is_none(),
+    };
+    let entries = parse(line.as_bytes(), None).unwrap_or_default();
+    let entry = match &entries[..] {
+        [] => argv.is_empty(),
+        [entry] => entry.name.is_none() && entry.env.is_empty() && entry.argv == argv,
+        _ => false,
+    };
+    tokens && entry
+}
+
+#[quickcheck]
+fn join_round_trips(argv: Vec<String>) -> bool {
+    round_trip(&argv)
+}
+
+#[quickcheck]
+fn join_round_trips_bytes(argv: Vec<Vec<u8>>) -> bool {
+    let mut tokens = Tokens::new(join_bytes(&argv), None).strict(true);
+    match tokens.next_bytes() {
+        None => argv.is_empty(),
+        Some(line) => line.ok() == Some(argv) && tokens.next_bytes().is_none(),
+    }
+}
+
+#[quickcheck]
+fn join_round_trips_special_chars(argv: Vec<Vec<u8>>) -> bool {
+    // Mostly the characters and prefixes treated specially, which are rare in arbitrary strings.
+    const PARTS: &[&str] =
+        &[" ", "\t", "\r", "\n", "\\", "'", "\"", "$", "#", "{", "}", ":", "=", "a", "web:", "A="];
+    let argv = argv
+        .iter()
+        .map(|arg| arg.iter().map(|&b| PARTS[b as usize % PARTS.len()]).collect())
+        .collect::<Vec<String>>();
+    round_trip(&argv)
+}
@@ -0,0 +1,427 @@
+//! Credentials, resource limits and scheduling of the child, applied before exec.
+
+use std::ffi::{
+    CStr,
+    CString,
+};
+use std::os::fd::{
+    AsRawFd,
+    FromRawFd,
+    OwnedFd,
+};
+use std::str::FromStr;
+use std::{
+    fmt,
+    io,
+};
+
+/// Attributes of the child process applied after fork and before exec.
+#[derive(Debug, Default, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct Attrs {
+    /// Run the child as the user, given by name or uid.
+    ///
+    /// The primary and supplementary groups are those of the user. A uid without an entry in the
+    /// user database keeps the primary group of subreaper unless `--group` is given, and has no
+    /// supplementary groups.
+    #[arg(long = "user", value_name = "USER")]
+    as_user: Option<String>,
+
+    /// Run the child with the primary group, given by name or gid, instead of the user's one.
+    #[arg(long = "group", value_name = "GROUP")]
+    as_group: Option<String>,
+
+    /// Prevent the child and its descendants from gaining privileges, e.g., via setuid binaries.
+    #[arg(long = "no-new-privs")]
+    no_new_privs: bool,
+
+    /// Set a resource limit of the child, e.g., `nofile=4096,core=0` or `nofile=1024:4096`.
+    ///
+    /// A single value sets both of the soft and hard limits. `unlimited` is also accepted.
+    #[arg(long = "rlimit", value_name = "RESOURCE=SOFT[:HARD]", value_delimiter = ',')]
+    pub(crate) rlimits: Vec<Rlimit>,
+
+    /// Set the niceness of the child, from -20 (the highest priority) to 19 (the lowest).
+    #[arg(long = "nice", value_name = "N", allow_negative_numbers = true,
+          value_parser = clap::value_parser!(i32).range(-20..=19))]
+    nice: Option<i32>,
+
+    /// Set the OOM score adjustment of the child, from -1000 to 1000.
+    #[arg(long = "oom-score-adj", value_name = "N", allow_negative_numbers = true,
+          value_parser = clap::value_parser!(i32).range(-1000..=1000))]
+    oom_score_adj: Option<i32>,
+
+    /// Pin the child to the CPUs, e.g., `0-3,6`.
+    #[arg(long = "cpu-affinity", value_name = "CPUS")]
+    pub(crate) cpu_affinity: Option<CpuList>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Rlimit {
+    pub(crate) resource: libc::c_int,
+    pub(crate) soft:     libc::rlim_t,
+    pub(crate) hard:     libc::rlim_t,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CpuList(pub(crate) Vec<usize>);
+
+const RESOURCES: &[(&str, libc::c_int)] = &[
+    ("as", libc::RLIMIT_AS as libc::c_int),
+    ("core", libc::RLIMIT_CORE as libc::c_int),
+    ("cpu", libc::RLIMIT_CPU as libc::c_int),
+    ("data", libc::RLIMIT_DATA as libc::c_int),
+    ("fsize", libc::RLIMIT_FSIZE as libc::c_int),
+    ("locks", libc::RLIMIT_LOCKS as libc::c_int),
+    ("memlock", libc::RLIMIT_MEMLOCK as libc::c_int),
+    ("msgqueue", libc::RLIMIT_MSGQUEUE as libc::c_int),
+    ("nice", libc::RLIMIT_NICE as libc::c_int),
+    ("nofile", libc::RLIMIT_NOFILE as libc::c_int),
+    ("nproc", libc::RLIMIT_NPROC as libc::c_int),
+    ("rss", libc::RLIMIT_RSS as libc::c_int),
+    ("rtprio", libc::RLIMIT_RTPRIO as libc::c_int),
+    ("rttime", libc::RLIMIT_RTTIME as libc::c_int),
+    ("sigpending", libc::RLIMIT_SIGPENDING as libc::c_int),
+    ("stack", libc::RLIMIT_STACK as libc::c_int),
+];
+
+/// A step of the setup before exec, reported if it fails.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Step {
+    Rlimit = 1,
+    Nice,
+    OomScoreAdj,
+    CpuAffinity,
+    Groups,
+    Gid,
+    Uid,
+    NoNewPrivs,
+    UserNamespace,
+    MountNamespace,
+    PidNamespace,
+    MountProc,
+}
+
+/// The attributes resolved before fork, so that applying them only makes system calls.
+pub(crate) struct Setup {
+    rlimits:       Vec<Rlimit>,
+    nice:          Option<i32>,
+    oom_score_adj: Option<CString>,
+    cpu_set:       Option<libc::cpu_set_t>,
+    groups:        Option<Vec<libc::gid_t>>,
+    gid:           Option<libc::gid_t>,
+    uid:           Option<libc::uid_t>,
+    no_new_privs:  bool,
+    /// The child writes the failed step into the pipe, which is closed on exec.
+    failure:       (OwnedFd, OwnedFd),
+}
+
+impl Attrs {
+    /// Resolves the attributes, or returns None if nothing to be applied.
+    pub(crate) fn setup(&self) -> io::Result<Option<Setup>> {
+        let noop = self.as_user.is_none()
+            && self.as_group.is_none()
+            && !self.no_new_privs
+            && self.rlimits.is_empty()
+            && self.nice.is_none()
+            && self.oom_score_adj.is_none()
+            && self.cpu_affinity.is_none();
+        if noop {
+            return Ok(None);
+        }
+
+        let user = self.as_user.as_deref().map(lookup_user).transpose()?;
+        let group = self.as_group.as_deref().map(lookup_group).transpose()?;
+        let gid = group.or(user.as_ref().and_then(|user| user.gid));
+        let groups = match (&user, gid) {
+            (Some(user), Some(gid)) => Some(user.groups(gid)?),
+            (Some(_), None) => Some(vec![unsafe { libc::getegid() }]),
+            // Drops the supplementary groups of subreaper.
+            (None, Some(gid)) => Some(vec![gid]),
+            _ => None,
+        };
+        // Without CAP_SETGID, the supplementary groups cannot be changed, but the primary group
+        // can still be set to the real or saved one, e.g., `--group` of the user's own gid.
+        let same_user = user.as_ref().is_none_or(|user| user.uid == unsafe { libc::geteuid() });
+        let groups = groups.filter(|_| !same_user || has_cap_setgid());
+
+        let cpu_set = self.cpu_affinity.as_ref().map(|cpus| {
+            let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+            for &cpu in &cpus.0 {
+                unsafe { libc::CPU_SET(cpu, &mut set) };
+            }
+            set
+        });
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let failure = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        Ok(Some(Setup {
+            rlimits: self.rlimits.clone(),
+            nice: self.nice,
+            oom_score_adj: self.oom_score_adj.map(|adj| CString::new(adj.to_string()).unwrap()),
+            cpu_set,
+            groups,
+            gid,
+            uid: user.map(|user| user.uid),
+            no_new_privs: self.no_new_privs,
+            failure,
+        }))
+    }
+}
+
+impl Setup {
+    /// Applies the attributes to the calling process, i.e., the child after fork.
+    ///
+    /// Only async-signal-safe functions are called here. Credentials are changed last, because
+    /// the other steps may require privileges.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        let check = |step: Step, ret: libc::c_int| {
+            if ret == 0 {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            let step = step as u8;
+            unsafe { libc::write(self.failure.1.as_raw_fd(), (&raw const step).cast(), 1) };
+            Err(err)
+        };
+
+        for rlimit in &self.rlimits {
+            let rlim = libc::rlimit { rlim_cur: rlimit.soft, rlim_max: rlimit.hard };
+            check(Step::Rlimit, unsafe { libc::setrlimit(rlimit.resource as _, &rlim) })?;
+        }
+        if let Some(nice) = self.nice {
+            check(Step::Nice, unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) })?;
+        }
+        if let Some(adj) = &self.oom_score_adj {
+            check(Step::OomScoreAdj, unsafe { write_file(c"/proc/self/oom_score_adj", adj) })?;
+        }
+        if let Some(set) = &self.cpu_set {
+            let size = std::mem::size_of::<libc::cpu_set_t>();
+            check(Step::CpuAffinity, unsafe { libc::sched_setaffinity(0, size, set) })?;
+        }
+        if let Some(groups) = &self.groups {
+            check(Step::Groups, unsafe { libc::setgroups(groups.len(), groups.as_ptr()) })?;
+        }
+        if let Some(gid) = self.gid {
+            check(Step::Gid, unsafe { libc::setgid(gid) })?;
+        }
+        if let Some(uid) = self.uid {
+            check(Step::Uid, unsafe { libc::setuid(uid) })?;
+        }
+        if self.no_new_privs {
+            check(Step::NoNewPrivs, unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+        }
+        Ok(())
+    }
+
+    /// Takes the step failed in the child, if spawning failed before exec.
+    pub(crate) fn failed(&self) -> Option<Step> {
+        let mut step = 0u8;
+        let n = unsafe { libc::read(self.failure.0.as_raw_fd(), (&raw mut step).cast(), 1) };
+        if n != 1 {
+            return None;
+        }
+        Step::from_u8(step)
+    }
+}
+
+impl Step {
+    /// Converts the byte written by the child back into the step.
+    pub(crate) fn from_u8(step: u8) -> Option<Step> {
+        [
+            Step::Rlimit,
+            Step::Nice,
+            Step::OomScoreAdj,
+            Step::CpuAffinity,
+            Step::Groups,
+            Step::Gid,
+            Step::Uid,
+            Step::NoNewPrivs,
+            Step::UserNamespace,
+            Step::MountNamespace,
+            Step::PidNamespace,
+            Step::MountProc,
+        ]
+        .into_iter()
+        .find(|s| *s as u8 == step)
+    }
+}
+
+/// Writes the contents into the file, without allocating.
+pub(crate) unsafe fn write_file(path: &CStr, contents: &CStr) -> libc::c_int {
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return -1;
+        }
+        let bytes = contents.to_bytes();
+        let n = libc::write(fd, bytes.as_ptr().cast(), bytes.len());
+        libc::close(fd);
+        if n == bytes.len() as isize { 0 } else { -1 }
+    }
+}
+
+struct User {
+    name: CString,
+    uid:  libc::uid_t,
+    /// The primary group, or None if the user has no entry in the user database.
+    gid:  Option<libc::gid_t>,
+}
+
+impl User {
+    /// Lists the groups of the user, including the primary group.
+    fn groups(&self, gid: libc::gid_t) -> io::Result<Vec<libc::gid_t>> {
+        let mut groups = vec![0; 64];
+        loop {
+            let mut n = groups.len() as libc::c_int;
+            let ret =
+                unsafe { libc::getgrouplist(self.name.as_ptr(), gid, groups.as_mut_ptr(), &mut n) };
+            if ret >= 0 {
+                groups.truncate(n as usize);
+                return Ok(groups);
+            }
+            // The buffer is too small, and n is set to the required size.
+            groups.resize((n as usize).max(groups.len() * 2), 0);
+        }
+    }
+}
+
+/// Looks up the user by name or uid.
+fn lookup_user(user: &str) -> io::Result<User> {
+    let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("no such user: {user}"));
+    let name = CString::new(user).map_err(|_| not_found())?;
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut pwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+    let mut found = std::ptr::null_mut();
+    let ret = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found)
+        },
+        Err(_) => unsafe {
+            libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found)
+        },
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    if found.is_null() {
+        // A uid without an entry in the user database.
+        return match user.parse::<libc::uid_t>() {
+            Ok(uid) => Ok(User { name, uid, gid: None }),
+            Err(_) => Err(not_found()),
+        };
+    }
+    let name = unsafe { CStr::from_ptr(pwd.pw_name) }.to_owned();
+    Ok(User { name, uid: pwd.pw_uid, gid: Some(pwd.pw_gid) })
+}
+
+/// Whether the calling process has CAP_SETGID in its effective capabilities.
+fn has_cap_setgid() -> bool {
+    const CAP_SETGID: u32 = 6;
+    let Ok(status) = std::fs::read_to_string("/proc/self/status") else {
+        return unsafe { libc::geteuid() } == 0;
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .is_some_and(|caps| caps & (1 << CAP_SETGID) != 0)
+}
+
+/// Looks up the group by name or gid.
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("no such group: {group}"));
+    let name = CString::new(group).map_err(|_| not_found())?;
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut grp = unsafe { std::mem::zeroed::<libc::group>() };
+    let mut found = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut found)
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    if found.is_null() {
+        return Err(not_found());
+    }
+    Ok(grp.gr_gid)
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Step::Rlimit => "setrlimit",
+            Step::Nice => "setpriority",
+            Step::OomScoreAdj => "oom_score_adj",
+            Step::CpuAffinity => "sched_setaffinity",
+            Step::Groups => "setgroups",
+            Step::Gid => "setgid",
+            Step::Uid => "setuid",
+            Step::NoNewPrivs => "PR_SET_NO_NEW_PRIVS",
+            Step::UserNamespace => "creating a user namespace",
+            Step::MountNamespace => "creating a mount namespace",
+            Step::PidNamespace => "creating a pid namespace",
+            Step::MountProc => "mounting /proc",
+        })
+    }
+}
+
+impl FromStr for Rlimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((resource, limits)) = s.split_once('=') else {
+            return Err(format!("expected RESOURCE=SOFT[:HARD], but got '{s}'"));
+        };
+        let resource = RESOURCES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(resource.trim()))
+            .map(|&(_, resource)| resource)
+            .ok_or_else(|| format!("unknown resource '{resource}'"))?;
+
+        let limit = |limit: &str| match limit.trim() {
+            "unlimited" | "infinity" => Ok(libc::RLIM_INFINITY),
+            limit => limit.parse::<libc::rlim_t>().map_err(|err| format!("'{limit}': {err}")),
+        };
+        let (soft, hard) = match limits.split_once(':') {
+            Some((soft, hard)) => (limit(soft)?, limit(hard)?),
+            None => (limit(limits)?, limit(limits)?),
+        };
+        if soft > hard {
+            return Err(format!("the soft limit exceeds the hard limit in '{s}'"));
+        }
+        Ok(Rlimit { resource, soft, hard })
+    }
+}
+
+impl FromStr for CpuList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let cpu = |cpu: &str| {
+            cpu.trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|&cpu| cpu < libc::CPU_SETSIZE as usize)
+                .ok_or_else(|| format!("invalid CPU '{cpu}'"))
+        };
+        let mut cpus = Vec::new();
+        for range in s.split(',') {
+            match range.split_once('-') {
+                Some((first, last)) => cpus.extend(cpu(first)?..=cpu(last)?),
+                None => cpus.push(cpu(range)?),
+            }
+        }
+        if cpus.is_empty() {
+            return Err(format!("no CPUs in '{s}'"));
+        }
+        Ok(CpuList(cpus))
+    }
+}
@@ -0,0 +1,267 @@
+//! Containing the child in a transient cgroup v2.
+
+use std::ffi::OsString;
+use std::io;
+use std::os::fd::{
+    AsRawFd,
+    OwnedFd,
+    RawFd,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Mutex;
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use tokio::{
+    fs,
+    time,
+};
+use tracing::{
+    error,
+    trace,
+};
+
+/// Containing the child in a transient cgroup v2, and limiting its resources.
+#[derive(Debug, Default, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct Cgroup {
+    /// Put the child into a transient cgroup created under the cgroup v2 directory.
+    ///
+    /// The directory must be writable, e.g., a subtree delegated to the user. All processes in
+    /// the cgroup are killed by `cgroup.kill` after the child exits, even if they have escaped
+    /// from the process group.
+    ///
+    /// Controllers required by the limits are enabled in `cgroup.subtree_control` of the parent
+    /// unless already enabled, which fails if the parent has processes, and are disabled again
+    /// once the transient cgroups are removed.
+    #[arg(long = "cgroup", value_name = "PARENT")]
+    cgroup_parent: Option<PathBuf>,
+
+    /// Limit the memory usage of the cgroup, e.g., `512M`. See `memory.max`.
+    #[arg(long = "memory-max", value_name = "BYTES", requires = "cgroup_parent")]
+    memory_max: Option<String>,
+
+    /// Limit the CPU bandwidth of the cgroup in microseconds, e.g., `50000/100000` for half a CPU.
+    /// See `cpu.max`.
+    #[arg(long = "cpu-max", value_name = "QUOTA[/PERIOD]", requires = "cgroup_parent")]
+    cpu_max: Option<String>,
+
+    /// Limit the number of processes in the cgroup. See `pids.max`.
+    #[arg(long = "pids-max", value_name = "N", requires = "cgroup_parent")]
+    pids_max: Option<u64>,
+}
+
+/// A cgroup created for the child, and removed when dropped.
+#[derive(Debug)]
+pub(crate) struct Transient {
+    path:   PathBuf,
+    parent: PathBuf,
+    /// `cgroup.procs` opened in advance, for the child to join the cgroup before exec.
+    procs:  OwnedFd,
+}
+
+/// Numbers the cgroups created by this process.
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Controllers enabled by this process in `cgroup.subtree_control` of the parents, which are
+/// disabled again once no cgroup is left under the parent.
+static ENABLED: Mutex<Vec<(PathBuf, &str)>> = Mutex::new(Vec::new());
+
+/// Polling interval while waiting for the cgroup to be empty.
+const KILL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long to wait for the cgroup to be empty after `cgroup.kill`.
+const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl Cgroup {
+    /// Creates a transient cgroup with the limits, if requested.
+    pub(crate) async fn create(&self) -> io::Result<Option<Transient>> {
+        let Some(parent) = self.cgroup_parent.as_ref() else {
+            return Ok(None);
+        };
+        let with_path = |path: &Path, err: io::Error| {
+            io::Error::new(err.kind(), format!("cgroup {}: {}", path.display(), err))
+        };
+
+        if !fs::try_exists(parent.join("cgroup.controllers")).await.unwrap_or(false) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("cgroup {}: not a cgroup v2 directory", parent.display()),
+            ));
+        }
+
+        let limits = [
+            ("memory", "memory.max", self.memory_max.clone()),
+            ("cpu", "cpu.max", self.cpu_max.as_ref().map(|max| max.replace('/', " "))),
+            ("pids", "pids.max", self.pids_max.map(|max| max.to_string())),
+        ];
+        let mut name = OsString::from(format!("subreaper.{}", std::process::id()));
+        name.push(format!(".{}", CREATED.fetch_add(1, Ordering::Relaxed)));
+        let path = parent.join(name);
+        fs::create_dir(&path).await.map_err(|err| {
+            let hint = match err.kind() {
+                io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+                    "; is it delegated and writable?"
+                }
+                _ => "",
+            };
+            io::Error::new(err.kind(), format!("cgroup {}: {}{}", path.display(), err, hint))
+        })?;
+
+        let procs = path.join("cgroup.procs");
+        let transient = Transient {
+            procs: fs::OpenOptions::new()
+                .write(true)
+                .open(&procs)
+                .await
+                .map_err(|err| with_path(&procs, err))?
+                .into_std()
+                .await
+                .into(),
+            path,
+            parent: parent.clone(),
+        };
+
+        // Enabled after creating the cgroup, which restores the parent when dropped on errors.
+        let controllers = limits.iter().filter(|(_, _, limit)| limit.is_some());
+        transient.enable(controllers.map(|(controller, _, _)| *controller)).await?;
+        for (_, file, limit) in limits {
+            if let Some(limit) = limit {
+                let file = transient.path.join(file);
+                fs::write(&file, limit).await.map_err(|err| with_path(&file, err))?;
+            }
+        }
+        Ok(Some(transient))
+    }
+}
+
+impl Transient {
+    /// Enables the controllers for the cgroups under the parent, unless already enabled.
+    async fn enable(&self, controllers: impl Iterator<Item = &'static str>) -> io::Result<()> {
+        let subtree_control = self.parent.join("cgroup.subtree_control");
+        let with_path = |err: io::Error| {
+            io::Error::new(err.kind(), format!("cgroup {}: {}", subtree_control.display(), err))
+        };
+        let enabled = fs::read_to_string(&subtree_control).await.map_err(with_path)?;
+        let missing = controllers
+            .filter(|controller| !enabled.split_whitespace().any(|enabled| enabled == *controller))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let write = missing.iter().map(|controller| format!("+{controller}")).collect::<Vec<_>>();
+        fs::write(&subtree_control, write.join(" ")).await.map_err(|err| {
+            if err.kind() != io::ErrorKind::ResourceBusy {
+                return with_path(err);
+            }
+            // Controllers are enabled only for the children of a cgroup without processes.
+            let hint = format!(
+                "{} has processes, e.g., subreaper itself; enable {} in advance, or use a leaf \
+                 cgroup without processes",
+                self.parent.display(),
+                missing.join(" "),
+            );
+            with_path(io::Error::new(err.kind(), format!("{err}; {hint}")))
+        })?;
+        let mut enabled = ENABLED.lock().unwrap();
+        enabled.extend(missing.into_iter().map(|controller| (self.parent.clone(), controller)));
+        Ok(())
+    }
+
+    /// Disables the controllers enabled by this process, once no cgroup is left under the parent.
+    fn restore(&self) {
+        let mut enabled = ENABLED.lock().unwrap();
+        // Other cgroups may depend on the controllers, e.g., those of the other procfile entries.
+        let in_use = std::fs::read_dir(&self.parent).map_or(true, |mut entries| {
+            entries.any(|entry| entry.and_then(|entry| entry.file_type()).is_ok_and(|t| t.is_dir()))
+        });
+        if in_use {
+            return;
+        }
+
+        let (ours, others) = std::mem::take(&mut *enabled)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(parent, _)| *parent == self.parent);
+        *enabled = others;
+        if ours.is_empty() {
+            return;
+        }
+        let write = ours.iter().map(|(_, controller)| format!("-{controller}")).collect::<Vec<_>>();
+        let subtree_control = self.parent.join("cgroup.subtree_control");
+        if let Err(err) = std::fs::write(&subtree_control, write.join(" ")) {
+            error!("failed to restore {}: {}", subtree_control.display(), err);
+        }
+    }
+
+    /// Moves the calling process into the cgroup.
+    ///
+    /// This is called after fork and before exec, so that it only uses the file opened in advance.
+    pub(crate) fn join(procs: RawFd) -> io::Result<()> {
+        // Writing 0 moves the writing process.
+        let n = unsafe { libc::write(procs, c"0".as_ptr().cast(), 1) };
+        if n < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+    }
+
+    pub(crate) fn procs_fd(&self) -> RawFd {
+        self.procs.as_raw_fd()
+    }
+
+    /// Counts the processes killed by the OOM killer so far. See `memory.events`.
+    pub(crate) async fn oom_kills(&self) -> u64 {
+        let Ok(events) = fs::read_to_string(self.path.join("memory.events")).await else {
+            return 0;
+        };
+        events
+            .lines()
+            .find_map(|line| line.strip_prefix("oom_kill "))
+            .and_then(|n| n.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Kills all processes left in the cgroup, and returns their pids.
+    pub(crate) async fn kill(&self) -> Vec<libc::pid_t> {
+        let pids = self.pids().await;
+        if pids.is_empty() {
+            return pids;
+        }
+
+        if let Err(err) = fs::write(self.path.join("cgroup.kill"), "1").await {
+            // `cgroup.kill` is not available before Linux 5.14.
+            trace!("cgroup.kill: {err}");
+            for &pid in &pids {
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+            }
+        }
+
+        let deadline = Instant::now() + KILL_TIMEOUT;
+        while !self.pids().await.is_empty() && Instant::now() < deadline {
+            time::sleep(KILL_INTERVAL).await;
+        }
+        pids
+    }
+
+    async fn pids(&self) -> Vec<libc::pid_t> {
+        fs::read_to_string(self.path.join("cgroup.procs"))
+            .await
+            .map(|procs| procs.lines().filter_map(|pid| pid.trim().parse().ok()).collect())
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for Transient {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir(&self.path) {
+            error!("failed to remove cgroup {}: {}", self.path.display(), err);
+        }
+        self.restore();
+    }
+}
@@ -0,0 +1,261 @@
+//! A unix socket to query and control the running subreaper, and its client.
+
+use std::io::{
+    self,
+    BufRead,
+    Write,
+};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net;
+use std::path::PathBuf;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::Instant;
+use std::{
+    fs,
+    process,
+};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+use serde_json::{
+    Value,
+    json,
+};
+use tokio::io::{
+    AsyncBufReadExt,
+    AsyncWriteExt,
+    BufReader,
+};
+use tokio::net::{
+    UnixListener,
+    UnixStream,
+};
+use tokio::task;
+use tracing::{
+    error,
+    trace,
+};
+
+use crate::signal::Signal;
+use crate::{
+    Flags,
+    Subreaper,
+    c,
+    procfs,
+};
+
+/// Exposes the state of subreaper to other tools.
+#[derive(Debug, Default, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct Control {
+    /// Listen on the unix socket for commands, answered with a JSON line each.
+    ///
+    /// Commands are `status`, `signal SIGNAL [child|group]`, `stop` and `pids`, one per line.
+    /// `subreaper ctl --socket PATH COMMAND` is the client.
+    #[arg(long = "control-socket", value_name = "PATH", conflicts_with = "procfile")]
+    socket: Option<PathBuf>,
+
+    /// Write the pid of subreaper into the file, which is removed on exit.
+    ///
+    /// Signals sent to subreaper are forwarded to the child, whose pid changes on restarts.
+    #[arg(long = "pid-file", value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+}
+
+/// The client of the control socket.
+#[derive(Debug, Clone, PartialEq, Eq, clap::Args)]
+pub(crate) struct Ctl {
+    /// The control socket of the running subreaper.
+    #[arg(long = "socket", value_name = "PATH")]
+    socket: PathBuf,
+
+    /// The command sent, e.g., `status` or `signal HUP`.
+    #[arg(required = true, trailing_var_arg = true)]
+    command: Vec<String>,
+}
+
+/// The state of the child shared with the control socket.
+#[derive(Debug, Default)]
+pub(crate) struct Live {
+    pub(crate) pid:        Option<u32>,
+    pub(crate) started_at: Option<Instant>,
+    pub(crate) spawned:    u32,
+    /// Orphans reaped in the previous runs.
+    pub(crate) orphans:    usize,
+}
+
+/// Serves the control socket until dropped.
+pub(crate) struct Server {
+    path:   PathBuf,
+    listen: task::JoinHandle<()>,
+}
+
+/// Removes the pid file when dropped.
+pub(crate) struct PidFile(PathBuf);
+
+struct State {
+    flags: Arc<ArcSwap<Flags>>,
+    live:  Arc<Mutex<Live>>,
+    stop:  mpsc::UnboundedSender<Signal>,
+}
+
+impl Control {
+    /// Binds the control socket, and returns the server with the stream of requests to stop the
+    /// child, which are handled in the same way as SIGTERM.
+    pub(crate) fn listen(
+        &self,
+        flags: Arc<ArcSwap<Flags>>,
+        live: Arc<Mutex<Live>>,
+    ) -> io::Result<(Option<Server>, BoxStream<'static, Signal>)> {
+        let Some(path) = self.socket.clone() else {
+            return Ok((None, stream::pending().boxed()));
+        };
+
+        // A stale socket left by a killed subreaper prevents binding. Anything else at the path,
+        // e.g., a mistyped data file, is not ours to remove and fails binding instead.
+        let socket = fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket());
+        if socket && net::UnixStream::connect(&path).is_err() {
+            let _ = fs::remove_file(&path);
+        }
+        let listener = UnixListener::bind(&path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+
+        let (stop, stops) = mpsc::unbounded();
+        let state = Arc::new(State { flags, live, stop });
+        let listen = task::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((conn, _)) => {
+                        task::spawn(serve(conn, state.clone()));
+                    }
+                    Err(err) => error!("control socket: {err}"),
+                }
+            }
+        });
+        Ok((Some(Server { path, listen }), stops.boxed()))
+    }
+
+    /// Writes the pid file, if requested.
+    pub(crate) fn pid_file(&self) -> io::Result<Option<PidFile>> {
+        let Some(path) = self.pid_file.clone() else {
+            return Ok(None);
+        };
+        fs::write(&path, format!("{}\n", process::id()))
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+        Ok(Some(PidFile(path)))
+    }
+}
+
+async fn serve(conn: UnixStream, state: Arc<State>) {
+    let (read, mut write) = conn.into_split();
+    let mut lines = BufReader::new(read).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(err) => return trace!("control socket: {err}"),
+        };
+        let mut response = state.respond(line.trim()).to_string();
+        response.push('\n');
+        if let Err(err) = write.write_all(response.as_bytes()).await {
+            return trace!("control socket: {err}");
+        }
+    }
+}
+
+impl State {
+    fn respond(&self, line: &str) -> Value {
+        let words = line.split_ascii_whitespace().collect::<Vec<_>>();
+        let result = match words[..] {
+            ["status"] => Ok(self.status()),
+            ["signal", signal] => self.signal(signal, None),
+            ["signal", signal, target] => self.signal(signal, Some(target)),
+            ["stop"] => self
+                .stop
+                .unbounded_send(Signal::TERM)
+                .map(|()| json!({}))
+                .map_err(|_| "the child has already been stopped".to_owned()),
+            ["pids"] => self.pids(),
+            _ => Err(format!("unknown command: {line}")),
+        };
+        match result {
+            Ok(mut response) => {
+                response["ok"] = json!(true);
+                response
+            }
+            Err(err) => json!({ "ok": false, "error": err }),
+        }
+    }
+
+    fn status(&self) -> Value {
+        let live = self.live.lock().unwrap();
+        json!({
+            "pid": process::id(),
+            "child": live.pid,
+            "uptime_ms": live.started_at.map(|started_at| started_at.elapsed().as_millis()),
+            "restarts": live.spawned.saturating_sub(1),
+            "orphans": live.orphans + Subreaper::unclaimed(),
+        })
+    }
+
+    fn signal(&self, signal: &str, target: Option<&str>) -> Result<Value, String> {
+        let signal = signal.parse::<Signal>()?;
+        let group = match target {
+            None => self.flags.load().kill.group,
+            Some("child") => false,
+            Some("group") => true,
+            Some(target) => return Err(format!("unknown target: {target}")),
+        };
+        let pid = self.live.lock().unwrap().pid.ok_or("the child is not running")?;
+        let sent = if group {
+            c::killpg(pid as libc::pid_t, signal.0)
+        } else {
+            c::kill(pid as libc::pid_t, signal.0)
+        };
+        sent.map(|()| json!({ "signal": signal.to_string(), "child": pid, "group": group }))
+            .map_err(|err| err.to_string())
+    }
+
+    fn pids(&self) -> Result<Value, String> {
+        let pids = procfs::descendants().map_err(|err| err.to_string())?;
+        let pids = pids.into_iter().filter(|&pid| !procfs::is_zombie(pid)).collect::<Vec<_>>();
+        Ok(json!({ "pids": pids }))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.listen.abort();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+impl Ctl {
+    /// Sends the command to the control socket, and prints the response.
+    ///
+    /// Fails if the response is not ok.
+    pub(crate) fn send(&self) -> anyhow::Result<()> {
+        let mut conn = net::UnixStream::connect(&self.socket)
+            .with_context(|| format!("Failed to connect to {}", self.socket.display()))?;
+        writeln!(conn, "{}", self.command.join(" "))?;
+
+        let mut response = String::new();
+        io::BufReader::new(conn).read_line(&mut response)?;
+        print!("{response}");
+        let ok =
+            serde_json::from_str::<Value>(&response).is_ok_and(|response| response["ok"] == true);
+        anyhow::ensure!(ok, "Got a failure response");
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+//! Mapping how the child has exited to the exit code of subreaper.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::signal::Signal;
+
+/// Which exit codes are successful, and how to translate them.
+#[derive(Debug, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct ExitCodes {
+    /// Exit codes regarded as success, after `--map-exit` is applied, e.g., `0,3,75`.
+    ///
+    /// Subreaper exits with zero on success, and the on-exit marker is created without `.err`.
+    #[arg(
+        long = "success-exit-codes",
+        value_name = "CODES",
+        value_delimiter = ',',
+        default_value = "0"
+    )]
+    success_codes: Vec<u8>,
+
+    /// Translate how the child has exited into an exit code, e.g.,
+    /// `75=0,timeout=0,signal:SEGV=139`.
+    ///
+    /// The left hand side is an exit code of the child, `timeout`, `idle-timeout`, or
+    /// `signal:SIGNAL` matching the signal which has terminated the child or requested subreaper
+    /// to stop it. Without a mapping, timeouts are 124, idle timeouts are 123, and signals are
+    /// 128 plus the signal number.
+    #[arg(long = "map-exit", value_name = "FROM=CODE", value_delimiter = ',')]
+    exit_map: Vec<Mapping>,
+}
+
+impl Default for ExitCodes {
+    fn default() -> Self {
+        ExitCodes { success_codes: vec![0], exit_map: Vec::new() }
+    }
+}
+
+/// Why the child has exited, in the order of precedence.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Cause {
+    IdleTimeout,
+    Timeout,
+    Signaled(Signal),
+    Exited(u8),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Mapping {
+    from: Cause,
+    to:   u8,
+}
+
+impl ExitCodes {
+    /// Returns the exit code for the cause.
+    pub(crate) fn code(&self, cause: Cause) -> u8 {
+        self.exit_map.iter().find(|mapping| mapping.from == cause).map_or(cause.code(), |m| m.to)
+    }
+
+    /// Tests if the exit code is regarded as success.
+    pub(crate) fn is_success(&self, code: u8) -> bool {
+        self.success_codes.contains(&code)
+    }
+}
+
+impl Cause {
+    /// The exit code without mappings.
+    fn code(self) -> u8 {
+        match self {
+            Cause::IdleTimeout => 123,
+            Cause::Timeout => 124,
+            Cause::Signaled(signal) => 128 + signal.0 as u8,
+            Cause::Exited(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cause::IdleTimeout => f.write_str("idle-timeout"),
+            Cause::Timeout => f.write_str("timeout"),
+            Cause::Signaled(signal) => write!(f, "signal:{signal}"),
+            Cause::Exited(code) => write!(f, "{code}"),
+        }
+    }
+}
+
+impl FromStr for Mapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((from, to)) = s.split_once('=') else {
+            return Err(format!("expected FROM=CODE, but got '{s}'"));
+        };
+        let from = match from.trim() {
+            "timeout" => Cause::Timeout,
+            "idle-timeout" => Cause::IdleTimeout,
+            from => match from.strip_prefix("signal:") {
+                Some(signal) => Cause::Signaled(signal.parse()?),
+                None => Cause::Exited(from.parse().map_err(|err| format!("'{from}': {err}"))?),
+            },
+        };
+        let to = to.trim().parse().map_err(|err| format!("'{to}': {err}"))?;
+        Ok(Mapping { from, to })
+    }
+}
@@ -0,0 +1,44 @@
+//! Running as the init process, i.e., pid 1 of a pid namespace such as a container.
+
+use crate::signal::FORWARDED;
+
+/// Running as the init process.
+#[derive(Debug, Default, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct Init {
+    /// Run as the init process, e.g., the entrypoint of a container.
+    ///
+    /// The kernel takes no default actions of signals sent to pid 1, so every terminating signal
+    /// is handled and forwarded to the process group of the child. Subreaper keeps reaping
+    /// zombies after the child exits, and exits only once all descendants are gone. They are left
+    /// to exit on their own, until SIGTERM or SIGINT is received, or `--kill-after` elapses.
+    #[arg(long = "init")]
+    pub(crate) init: bool,
+}
+
+impl Init {
+    /// Signals to be forwarded to the child.
+    pub(crate) fn signals(&self) -> Vec<libc::c_int> {
+        if !self.init {
+            return FORWARDED.to_vec();
+        }
+
+        // Synchronous signals, e.g., SIGSEGV, are not sent by others, and SIGPIPE is ignored.
+        let terminating = [
+            libc::SIGALRM,
+            libc::SIGVTALRM,
+            libc::SIGPROF,
+            libc::SIGXCPU,
+            libc::SIGXFSZ,
+            libc::SIGIO,
+            libc::SIGPWR,
+            libc::SIGSTKFLT,
+        ];
+        let realtime = libc::SIGRTMIN()..=libc::SIGRTMAX();
+        FORWARDED.iter().copied().chain(terminating).chain(realtime).collect()
+    }
+}
+
+/// Tests if the current process is pid 1 of its pid namespace.
+pub(crate) fn is_pid1() -> bool {
+    std::process::id() == 1
+}
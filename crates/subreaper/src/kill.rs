@@ -0,0 +1,245 @@
+//! Policies to stop the child, and to clean up its descendants.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use tokio::time;
+use tracing::{
+    error,
+    trace,
+};
+
+use crate::signal::Signal;
+use crate::{
+    Subreaper,
+    c,
+    procfs,
+};
+
+/// How to stop the child on timeouts or forwarded signals, and the descendants left behind.
+#[derive(Debug, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct Kill {
+    /// The signal sent first to stop the child.
+    #[arg(long = "kill-signal", value_name = "SIGNAL", default_value = "TERM")]
+    signal: Signal,
+
+    /// Send SIGKILL if the child is still running after the duration since the first signal.
+    ///
+    /// Also applies to the descendants left behind, hence `--drain-grace` is accepted as well.
+    #[arg(
+        long = "kill-grace",
+        visible_alias = "drain-grace",
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration,
+        default_value = "500ms",
+    )]
+    grace: Duration,
+
+    /// Send signals to the process group of the child, instead of the child only.
+    #[arg(long = "kill-group")]
+    pub(crate) group: bool,
+
+    /// Escalate signals step by step, e.g., `INT:2s,TERM:5s,KILL`.
+    ///
+    /// Each step waits for the duration before going to the next step. SIGKILL is appended
+    /// if the last step has a duration. Overrides `--kill-signal` and `--kill-grace`.
+    #[arg(
+        long = "kill-ladder",
+        value_name = "SIGNAL:DURATION,...",
+        conflicts_with_all = ["signal", "grace"],
+    )]
+    ladder: Option<Ladder>,
+}
+
+impl Default for Kill {
+    fn default() -> Self {
+        Kill {
+            signal: Signal::TERM,
+            grace:  Duration::from_millis(500),
+            group:  false,
+            ladder: None,
+        }
+    }
+}
+
+/// Signals to be sent in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Ladder(Vec<Step>);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Step {
+    signal: Signal,
+    /// How long to wait before the next step. Only the last step has none.
+    grace:  Option<Duration>,
+}
+
+impl Kill {
+    /// Returns the steps to kill, the last of which has no grace period.
+    pub(crate) fn ladder(&self) -> Vec<Step> {
+        match &self.ladder {
+            Some(Ladder(steps)) => steps.clone(),
+            None => vec![
+                Step { signal: self.signal, grace: Some(self.grace) },
+                Step { signal: Signal::KILL, grace: None },
+            ],
+        }
+    }
+
+    /// Sends the signal to the child, or to its process group.
+    pub(crate) fn send(&self, pid: u32, signal: Signal) {
+        if self.group {
+            killpg(pid, signal);
+        } else {
+            kill(pid as libc::pid_t, signal);
+        }
+    }
+
+    /// Sends signals along the ladder, until the last step.
+    ///
+    /// If given, `first` is sent instead of the signal of the first step. This is intended to be
+    /// cancelled, i.e., dropped, when the child exits.
+    pub(crate) async fn escalate(self, pid: u32, first: Option<Signal>) {
+        for (i, step) in self.ladder().into_iter().enumerate() {
+            let signal = if i == 0 { first.unwrap_or(step.signal) } else { step.signal };
+            trace!(pid = pid, "escalate: {}", signal);
+            self.send(pid, signal);
+            if let Some(grace) = step.grace {
+                time::sleep(grace).await;
+            }
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.grace {
+            Some(grace) => write!(f, "{}:{}", self.signal, humantime::format_duration(grace)),
+            None => write!(f, "{}", self.signal),
+        }
+    }
+}
+
+impl FromStr for Ladder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut steps = Vec::new();
+        for step in s.split(',') {
+            if steps.last().is_some_and(|last: &Step| last.grace.is_none()) {
+                return Err(format!("only the last step can omit the duration: {s}"));
+            }
+            let step = match step.split_once(':') {
+                Some((signal, grace)) => Step {
+                    signal: signal.trim().parse()?,
+                    grace:  Some(
+                        humantime::parse_duration(grace.trim()).map_err(|e| e.to_string())?,
+                    ),
+                },
+                None => Step { signal: step.trim().parse()?, grace: None },
+            };
+            steps.push(step);
+        }
+
+        if steps.last().is_some_and(|last| last.grace.is_some()) {
+            steps.push(Step { signal: Signal::KILL, grace: None });
+        }
+        Ok(Ladder(steps))
+    }
+}
+
+fn kill(pid: libc::pid_t, signal: Signal) {
+    if let Err(err) = c::kill(pid, signal.0) {
+        trace!(pid = pid, "kill({}): {}", signal, err);
+    }
+}
+
+fn killpg(pid: u32, signal: Signal) {
+    if let Err(err) = c::killpg(pid as libc::c_int, signal.0) {
+        trace!(pid = pid, "killpg({}): {}", signal, err);
+    }
+}
+
+/// Polling interval while waiting for the descendants to be reaped.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long to wait for the descendants to be reaped after the last step.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Terminates the descendants left behind after the child exits.
+///
+/// Daemonized descendants may have left the process group of the child, so they are found by
+/// walking the process tree instead, if the process is dedicated to subreaper and no other child
+/// is running. Otherwise, only the process group of the child and its subtree are drained. The
+/// descendants are signaled along the ladder, and the pids still alive at the last step are
+/// returned as stragglers.
+pub(crate) async fn drain(pgid: u32, policy: &Kill) -> Vec<libc::pid_t> {
+    let mut pids: Vec<libc::pid_t> = alive(pgid);
+    for step in policy.ladder() {
+        if pids.is_empty() {
+            return pids;
+        }
+
+        killpg(pgid, step.signal);
+        for &pid in &pids {
+            kill(pid, step.signal);
+        }
+
+        let Some(grace) = step.grace else {
+            break;
+        };
+        let deadline = Instant::now() + grace;
+        while !pids.is_empty() && Instant::now() < deadline {
+            time::sleep(DRAIN_INTERVAL).await;
+            pids = alive(pgid);
+        }
+    }
+
+    // Give the subreaper a chance to reap what have been killed.
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while !descendants(pgid).is_empty() && Instant::now() < deadline {
+        time::sleep(DRAIN_INTERVAL).await;
+    }
+
+    pids
+}
+
+/// Waits until all descendants are gone, sending the signal to those still alive if given.
+///
+/// Unlike [drain], this never gives up, e.g., as the init process.
+pub(crate) async fn wait_gone(pgid: u32, signal: Option<Signal>) {
+    let mut pids = descendants(pgid);
+    while !pids.is_empty() {
+        if let Some(signal) = signal {
+            for pid in alive(pgid) {
+                kill(pid, signal);
+            }
+        }
+        time::sleep(DRAIN_INTERVAL).await;
+        pids = descendants(pgid);
+    }
+}
+
+/// Lists the descendants left behind by the child, which led the process group.
+fn descendants(pgid: u32) -> Vec<libc::pid_t> {
+    let pids = if Subreaper::dedicated() && !Subreaper::supervising() {
+        // Orphans reparented to subreaper are swept once the last child exits.
+        procfs::descendants()
+    } else {
+        // Orphans may belong to other children still running, e.g., procfile entries, and the
+        // other children of the process embedding subreaper are not ours to kill.
+        procfs::group(pgid as libc::pid_t)
+    };
+    pids.unwrap_or_else(|err| {
+        error!("failed to list descendants: {}", err);
+        Vec::new()
+    })
+}
+
+/// Lists the live descendants. Zombies are not stragglers, they will be reaped by the subreaper.
+fn alive(pgid: u32) -> Vec<libc::pid_t> {
+    descendants(pgid).into_iter().filter(|&pid| !procfs::is_zombie(pid)).collect()
+}
@@ -1,39 +1,51 @@
 //! Child process subreaper.
 
+use std::collections::HashMap;
 use std::ffi::OsString;
-use std::io;
 use std::os::unix::process::ExitStatusExt;
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::process::{
     self,
     ExitCode,
     Stdio,
     Termination,
 };
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
 use std::sync::{
     Arc,
     LazyLock,
+    Mutex,
+};
+use std::task::Poll;
+use std::time::{
+    Duration,
+    Instant,
+    SystemTime,
+};
+use std::{
+    io,
+    thread,
 };
-use std::time::Duration;
 
 use anyhow::Context;
 use arc_swap::ArcSwap;
-use futures::future;
+use futures::future::BoxFuture;
 use futures::prelude::*;
-use tokio::io::{
-    AsyncBufReadExt,
-    BufReader,
-};
+use futures::stream::BoxStream;
+use tokio::io::AsyncRead;
 use tokio::signal::unix::{
     SignalKind,
     signal,
 };
-use tokio::sync::broadcast;
-use tokio::sync::broadcast::error::SendError;
-use tokio::{
-    task,
-    time,
+use tokio::sync::{
+    oneshot,
+    watch,
 };
+use tokio::time;
 use tracing::{
     error,
     info,
@@ -42,8 +54,71 @@ use tracing::{
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::prelude::*;
 
+use crate::attrs::{
+    Attrs,
+    Setup,
+    Step,
+};
+use crate::cgroup::{
+    Cgroup,
+    Transient,
+};
+use crate::control::{
+    Control,
+    Ctl,
+    Live,
+    Server,
+};
+use crate::exit::{
+    Cause,
+    ExitCodes,
+};
+use crate::init::Init;
+use crate::kill::Kill;
+pub use crate::output::Mode as OutputMode;
+use crate::output::{
+    Output,
+    Relays,
+};
+use crate::procfile::Procfile;
+use crate::pty::{
+    Pty,
+    Terminal,
+};
+use crate::report::Report;
+use crate::restart::{
+    Restart,
+    Restarts,
+};
+use crate::rusage::Rusage;
+use crate::sandbox::{
+    Sandbox,
+    Unshare,
+};
+use crate::signal::{
+    Forward,
+    Forwarded,
+    Signal,
+};
+use crate::wait::Wait;
+
+mod attrs;
+mod cgroup;
+mod control;
+mod exit;
+mod init;
+mod kill;
+mod output;
+mod procfile;
+mod pty;
+mod report;
+mod restart;
+mod rusage;
+mod sandbox;
+mod signal;
 #[cfg(test)]
 mod tests;
+mod wait;
 
 /// ProcExit implements Termination.
 #[derive(Debug)]
@@ -66,7 +141,12 @@ pub fn run<T>(args: impl IntoIterator<Item = T>) -> ProcExit
 where
     T: Into<OsString> + Clone,
 {
-    ProcExit(try_run(args))
+    // The exit status of the child is reported by the exit code, anything else is not.
+    ProcExit(try_run(args).inspect_err(|err| {
+        if err.downcast_ref::<ExitStatusError>().is_none() {
+            eprintln!("{err:#}");
+        }
+    }))
 }
 
 /// Spawns a new process and waits the status.
@@ -75,6 +155,7 @@ async fn try_run<T>(args: impl IntoIterator<Item = T>) -> anyhow::Result<()>
 where
     T: Into<OsString> + Clone,
 {
+    DEDICATED.store(true, Ordering::Relaxed);
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
@@ -85,139 +166,265 @@ where
                 .without_time(),
         )
         .with(EnvFilter::from_env("SUBREAPER_LOG"))
-        .init();
-
-    let proc =
-        Flags::from_args_os(args).command().spawn().await.context("Failed to spawn process")?;
+        .try_init()
+        .ok();
 
-    proc.wait()
-        .await
-        .context("Failed to fetch wait status")?
-        .exit_ok()
-        .context("Got a failure on running the process")
+    let flags = Flags::from_args_os(args);
+    if let Some(Subcommand::Ctl(ctl)) = &flags.subcommand {
+        return ctl.send();
+    }
+    let _pid_file = flags.control.pid_file().context("Failed to write the pid file")?;
+    let status = if flags.procfile.path.is_some() {
+        flags.procfile.clone().run(flags).await.context("Failed to run the procfile")
+    } else {
+        let proc = flags.command().spawn().await.context("Failed to spawn process")?;
+        proc.wait().await.context("Failed to fetch wait status")
+    };
+
+    status?.exit_ok().context("Got a failure on running the process")
 }
 
+/// How long to wait for stdout/stderr to be closed after the child exits.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Whether the process is dedicated to subreaper, i.e., runs [`run`].
+///
+/// Only then the process becomes a subreaper, which reaps every child of its own and drains all
+/// descendants. Otherwise, e.g., embedded by [`Supervisor`], only the children spawned by
+/// subreaper are reaped, and the other children of the process are left alone.
+static DEDICATED: AtomicBool = AtomicBool::new(false);
+
 static SUBREAPER: LazyLock<Subreaper> = LazyLock::new(|| {
+    let dedicated = DEDICATED.load(Ordering::Relaxed);
+    // Orphans are reparented to pid 1 anyway.
     #[cfg(target_os = "linux")]
-    unsafe {
-        assert_eq!(0, libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0));
+    if dedicated && !init::is_pid1() {
+        unsafe {
+            assert_eq!(0, libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0));
+        }
     }
-    Subreaper::start()
+    Subreaper::start(dedicated)
 });
 
+/// Reaps child processes, and delivers their exit statuses to the waiters.
+///
+/// The reaper runs on its own thread, so that it outlives the runtime which first spawns a child,
+/// e.g., one of the runtimes of tests.
 struct Subreaper {
-    tx: broadcast::Sender<(libc::c_int, process::ExitStatus)>,
-    #[allow(dead_code)]
-    jh: task::JoinHandle<usize>,
+    table:     Arc<Mutex<ExitTable>>,
+    /// Reaps any child, not only the ones spawned by subreaper. See [`DEDICATED`].
+    dedicated: bool,
+}
+
+/// Exit statuses keyed by pid.
+///
+/// An exit status is delivered exactly once, either to the waiter registered before reaping, or
+/// to the first waiter after reaping. Since the table is keyed by pid, the number of exit
+/// statuses nobody waits for is bounded by the maximum pid.
+#[derive(Debug, Default)]
+struct ExitTable {
+    /// Reaped, but no one has waited for yet.
+    exited:  HashMap<libc::pid_t, Reaped>,
+    /// Waiting for, but not reaped yet.
+    waiters: HashMap<libc::pid_t, oneshot::Sender<Reaped>>,
 }
 
+/// The exit status and the resource usage of a reaped process.
+#[derive(Debug, Copy, Clone)]
+struct Reaped {
+    exit_status: process::ExitStatus,
+    rusage:      Rusage,
+}
+
+/// Resolves to the exit status of a process reaped by the subreaper.
+#[derive(Debug)]
+struct Exit(oneshot::Receiver<Reaped>);
+
 impl Subreaper {
-    fn start() -> Self {
-        let (tx, _rx) = broadcast::channel(16);
-        let tx_cloned = tx.clone();
-        let jh = task::spawn(async move {
-            let mut signal = signal(SignalKind::child()).expect("failed to create a signal");
-            let mut reaped = 0;
+    fn start(dedicated: bool) -> Self {
+        let table = Arc::new(Mutex::new(ExitTable::default()));
+        let table_cloned = Arc::clone(&table);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to build a runtime for the reaper");
+        // Registers the handler before spawning the thread, not to miss SIGCHLD sent before the
+        // reaper starts running.
+        let mut signal = {
+            let _guard = runtime.enter();
+            signal(SignalKind::child()).expect("failed to create a signal")
+        };
+        let reaper = async move {
             while signal.recv().await.is_some() {
-                loop {
-                    // Waits for any child process.
-                    //
-                    // The WNOHANG option is used to indicate that the call should not block
-                    // if there are no processes that wish to report status.
-                    let mut status: libc::c_int = 0;
-                    match unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) } {
-                        -1 => {
-                            // If RawOsError was constructed via last_os_error,
-                            // then this function always return Some.
-                            match io::Error::last_os_error().raw_os_error().unwrap() {
-                                libc::ECHILD => {
-                                    trace!("ECHILD: no children that it has not yet waited for");
-                                    break;
-                                }
-                                libc::EINTR => {
-                                    // This likely can't happen since we are calling libc::waitpid
-                                    // with WNOHANG.
-                                    trace!("EINTR: got interrupted, continue reaping");
-                                }
-                                errno => {
-                                    trace!(
-                                        "got an error({}), or caught signal aborts the call",
-                                        errno,
-                                    );
-                                }
-                            }
-                        }
-                        0 => {
-                            trace!("no children wish to report status");
-                            break;
-                        }
-                        pid => {
-                            match tx.send((pid, process::ExitStatus::from_raw(status))) {
-                                Ok(_subscribers) => {}
-                                Err(SendError((pid, exit_status))) => {
-                                    trace!(
-                                        reaped = pid,
-                                        exit_status = exit_status.code(),
-                                        "reaped but no active receivers WIFEXITED({}) WEXITSTATUS({})",
-                                        libc::WIFEXITED(status),
-                                        libc::WEXITSTATUS(status)
-                                    );
-                                }
-                            }
-
-                            reaped += 1;
+                // Holds the lock while reaping. If spawning fails after fork, e.g., in pre_exec,
+                // the failed child is left to be waited for by the spawning thread.
+                let mut table = table.lock().unwrap();
+                if dedicated {
+                    // Reaps any child process, including orphans reparented to subreaper.
+                    while let Some((pid, exit)) = reap(-1) {
+                        table.exited(pid, exit);
+                    }
+                } else {
+                    // Other children of the process are left to be waited for by their owners.
+                    let pids = table.waiters.keys().copied().collect::<Vec<_>>();
+                    for pid in pids {
+                        if let Some((pid, exit)) = reap(pid) {
+                            table.exited(pid, exit);
                         }
                     }
                 }
             }
+        };
+        // Detached, since the reaper runs as long as the process.
+        thread::Builder::new()
+            .name("subreaper".to_owned())
+            .spawn(move || runtime.block_on(reaper))
+            .expect("failed to spawn the reaper");
+
+        Subreaper { table: table_cloned, dedicated }
+    }
+
+    /// Spawns the command, and returns the child and its pid with its exit status to be reaped.
+    ///
+    /// The exit table is locked while spawning. Thus the child cannot be reaped before the waiter
+    /// is registered, and a stale exit status of the reused pid is never delivered to the child.
+    ///
+    /// If the command unshares a pid namespace, the init of it is waited for instead, since the
+    /// child exits right after forking the init.
+    fn spawn(
+        cmd: &mut tokio::process::Command,
+        unshare: Option<&Unshare>,
+    ) -> io::Result<(tokio::process::Child, u32, Exit)> {
+        let mut table = SUBREAPER.table.lock().unwrap();
+        let child = cmd.spawn()?;
+        let pid = match unshare {
+            Some(unshare) => unshare.init_pid()?,
+            None => child.id().expect("fetching the process id before polling should not fail"),
+        };
+        table.exited.remove(&(pid as libc::pid_t));
+        let exit = table.waiter(pid as libc::pid_t);
+        Ok((child, pid, exit))
+    }
 
-            reaped
-        });
+    /// Takes the processes reaped but nobody has waited for, i.e., orphans.
+    fn take_unclaimed() -> Vec<(libc::pid_t, Rusage)> {
+        let mut orphans = SUBREAPER
+            .table
+            .lock()
+            .unwrap()
+            .exited
+            .drain()
+            .map(|(pid, reaped)| (pid, reaped.rusage))
+            .collect::<Vec<_>>();
+        orphans.sort_unstable_by_key(|(pid, _)| *pid);
+        orphans
+    }
 
-        Subreaper { tx: tx_cloned, jh }
+    /// Counts the processes reaped but nobody has waited for yet.
+    fn unclaimed() -> usize {
+        SUBREAPER.table.lock().unwrap().exited.len()
     }
 
-    /// Gets a receiver channel.
-    fn subscribe() -> Channel {
-        Channel { rx: SUBREAPER.tx.subscribe() }
+    /// Whether every child is reaped by subreaper. See [`DEDICATED`].
+    fn dedicated() -> bool {
+        SUBREAPER.dedicated
     }
 
-    // Aborts the reaper.
-    // fn abort() {
-    //     SUBREAPER.jh.abort();
-    // }
+    /// Whether any child spawned by subreaper is not reaped yet.
+    fn supervising() -> bool {
+        !SUBREAPER.table.lock().unwrap().waiters.is_empty()
+    }
 }
 
-/// Receiving the results of reaping.
-struct Channel {
-    rx: broadcast::Receiver<(libc::c_int, process::ExitStatus)>,
+/// Reaps the child process, or any child if `pid` is -1, and fetches its resource usage.
+///
+/// Returns None if no child wishes to report status.
+fn reap(pid: libc::pid_t) -> Option<(libc::pid_t, Reaped)> {
+    loop {
+        // The WNOHANG option is used to indicate that the call should not block
+        // if there are no processes that wish to report status.
+        let mut status: libc::c_int = 0;
+        let mut rusage = unsafe { std::mem::zeroed::<libc::rusage>() };
+        match unsafe { libc::wait4(pid, &mut status, libc::WNOHANG, &mut rusage) } {
+            -1 => {
+                // If RawOsError was constructed via last_os_error,
+                // then this function always return Some.
+                match io::Error::last_os_error().raw_os_error().unwrap() {
+                    libc::ECHILD => {
+                        trace!("ECHILD: no children that it has not yet waited for");
+                        return None;
+                    }
+                    libc::EINTR => {
+                        // This likely can't happen since we are calling libc::wait4
+                        // with WNOHANG.
+                        trace!("EINTR: got interrupted, continue reaping");
+                    }
+                    errno => {
+                        trace!("got an error({}), or caught signal aborts the call", errno);
+                        return None;
+                    }
+                }
+            }
+            0 => {
+                trace!("no children wish to report status");
+                return None;
+            }
+            pid => {
+                trace!(
+                    reaped = pid,
+                    "WIFEXITED({}) WEXITSTATUS({})",
+                    libc::WIFEXITED(status),
+                    libc::WEXITSTATUS(status)
+                );
+                let reaped = Reaped {
+                    exit_status: process::ExitStatus::from_raw(status),
+                    rusage:      Rusage::from(rusage),
+                };
+                return Some((pid, reaped));
+            }
+        }
+    }
 }
 
-/// Returns when recv failed.
-#[derive(Debug, PartialEq, Eq, Clone)]
-struct RecvError(broadcast::error::RecvError);
+impl ExitTable {
+    /// Delivers the exit status to the waiter, or holds it until waited for.
+    fn exited(&mut self, pid: libc::pid_t, reaped: Reaped) {
+        match self.waiters.remove(&pid) {
+            Some(tx) => {
+                if let Err(reaped) = tx.send(reaped) {
+                    trace!(reaped = pid, code = reaped.exit_status.code(), "the waiter has gone");
+                }
+            }
+            None => {
+                self.exited.insert(pid, reaped);
+            }
+        }
+    }
 
-impl Channel {
-    /// Receives the results of reaping.
-    pub async fn recv(&mut self) -> Result<(libc::c_int, process::ExitStatus), RecvError> {
-        // TODO: Consider not to return Closed.
-        self.rx.recv().await.map_err(RecvError)
+    /// Registers a waiter for the process.
+    fn waiter(&mut self, pid: libc::pid_t) -> Exit {
+        let (tx, rx) = oneshot::channel();
+        match self.exited.remove(&pid) {
+            Some(reaped) => {
+                let _ = tx.send(reaped);
+            }
+            None => {
+                if self.waiters.insert(pid, tx).is_some() {
+                    trace!(pid = pid, "replaced the previous waiter");
+                }
+            }
+        }
+        Exit(rx)
     }
 }
 
-impl RecvError {
-    /// There are no active reaper.
-    pub fn closed(&self) -> bool {
-        matches!(self.0, broadcast::error::RecvError::Closed)
-    }
+impl Future for Exit {
+    type Output = io::Result<Reaped>;
 
-    /// The receiver lagged too far behind.
-    ///
-    /// Attempting to receive again will return the oldest message
-    /// still retained by the channel.
-    /// Includes the number of skipped messages.
-    pub fn lagged(&self) -> Option<u64> {
-        if let broadcast::error::RecvError::Lagged(n) = self.0 { Some(n) } else { None }
+    fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|r| r.map_err(|_| io::Error::other("the subreaper has gone")))
     }
 }
 
@@ -250,25 +457,80 @@ mod fsutil {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, clap::Parser)]
+/// Spawns a program, waits for it, and reaps the orphans left behind.
+#[derive(Debug, Default, Clone, PartialEq, Eq, clap::Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Flags {
+    #[command(subcommand)]
+    subcommand: Option<Subcommand>,
+
+    #[command(flatten)]
+    wait: Wait,
+
     #[command(flatten)]
     hook: Hook,
 
     #[command(flatten)]
     timeout: Timeout,
 
-    /// Environment variables visible to the spawned process.
-    #[arg(long = "env", value_name = "KEY")]
-    envs: Vec<String>,
+    #[command(flatten)]
+    exit_codes: ExitCodes,
+
+    #[command(flatten)]
+    kill: Kill,
+
+    #[command(flatten)]
+    forward: Forward,
+
+    #[command(flatten)]
+    env: Env,
+
+    #[command(flatten)]
+    output: Output,
+
+    #[command(flatten)]
+    restart: Restart,
+
+    #[command(flatten)]
+    cgroup: Cgroup,
+
+    #[command(flatten)]
+    attrs: Attrs,
+
+    #[command(flatten)]
+    terminal: Terminal,
+
+    #[command(flatten)]
+    sandbox: Sandbox,
+
+    #[command(flatten)]
+    init: Init,
+
+    #[command(flatten)]
+    procfile: Procfile,
+
+    #[command(flatten)]
+    control: Control,
 
     /// The entrypoint of the child process.
-    #[arg()]
-    program: OsString,
+    #[arg(required_unless_present = "procfile")]
+    program: Option<OsString>,
 
     /// The arguments passed to the command.
     #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     args: Vec<OsString>, // CMD
+
+    /// The name of the procfile entry.
+    #[arg(skip)]
+    name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, clap::Subcommand)]
+enum Subcommand {
+    /// Send a command to the control socket of a running subreaper, and print the response.
+    ///
+    /// Run `subreaper -- ctl` to supervise a program named `ctl` instead.
+    Ctl(Ctl),
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, clap::Parser)]
@@ -281,61 +543,195 @@ struct Timeout {
     )]
     kill_after: Option<Duration>,
 
-    /// Exit with a zero status on timeout.
+    /// Exit with a zero status on timeout, but not on idle timeout.
     // For example, timeout is not a failure for '//fuzzing:fuzz_test'.
     #[arg(long = "timeout-is-ok")]
     is_ok: bool,
-}
 
-#[derive(Debug, Clone, PartialEq, Eq, clap::Parser)]
-struct Hook {
-    /// Check existence of given files before spawning the child process.
+    /// Kill the spawned process if it writes nothing to stdout and stderr for the duration.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    idle_timeout: Option<Duration>,
+
+    /// Send `--warn-signal` to the spawned process if it still running after the duration.
     ///
-    /// Note that the timeout duration does not elapse until the child is spawned.
-    /// So the operations before spawning, i.e., waiting for files, never times out.
-    #[arg(long = "wait", value_name = "PATH")]
-    wait_for: Vec<PathBuf>,
+    /// Intended to be shorter than `--kill-after`, e.g., to get a thread dump before killing.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    warn_after: Option<Duration>,
+
+    /// The signal sent after `--warn-after`.
+    #[arg(long, value_name = "SIGNAL", default_value = "QUIT", requires = "warn_after")]
+    warn_signal: Signal,
+}
 
+impl Default for Timeout {
+    fn default() -> Self {
+        Timeout {
+            kill_after:   None,
+            is_ok:        false,
+            idle_timeout: None,
+            warn_after:   None,
+            warn_signal:  Signal::QUIT,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, clap::Parser)]
+struct Hook {
     /// Create an empty file after the child process exits.
     #[arg(long, value_name = "PATH")]
     on_exit: Option<PathBuf>,
+
+    /// Write a JSON report after the child process exits.
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, clap::Parser)]
+struct Env {
+    /// Start the spawned process with an empty environment instead of inheriting ours.
+    #[arg(long = "clear-env")]
+    clear: bool,
+
+    /// Read environment variables from the file, which consists of `KEY=VALUE` lines.
+    ///
+    /// Quoting and `$VAR` references are interpreted in the same way as procrc files.
+    /// Variables are referenced from the environment of subreaper.
+    #[arg(long = "env-file", value_name = "PATH")]
+    files: Vec<PathBuf>,
+
+    /// Set an environment variable visible to the spawned process.
+    ///
+    /// `KEY=VALUE` sets the variable, and `KEY` passes through the variable of subreaper.
+    /// Applied after `--env-file`, so this takes precedence.
+    #[arg(long = "env", value_name = "KEY[=VALUE]")]
+    vars: Vec<String>,
 }
 
 struct Command {
-    cmd:   tokio::process::Command,
-    flags: Arc<ArcSwap<Flags>>,
+    cmd:     tokio::process::Command,
+    flags:   Arc<ArcSwap<Flags>>,
+    cgroup:  Option<Arc<Transient>>,
+    setup:   Option<Arc<Setup>>,
+    unshare: Option<Arc<Unshare>>,
+    live:    Arc<Mutex<Live>>,
 }
 
+/// Supervises the child, restarting it according to the restart policy.
+///
+/// Unlike [`run`], the supervisor installs no tracing subscriber, and does not listen to signals
+/// unless requested. The calling process does not become a subreaper either. Only the children
+/// spawned by the supervisor are reaped, and other children of the process are left alone. The
+/// descendants left behind are drained from the process group of the child, so those which have
+/// left the group and the tree, e.g., daemons, are out of reach unless confined in a cgroup.
+///
+/// ```no_run
+/// # async fn supervise() -> std::io::Result<()> {
+/// use std::time::Duration;
+///
+/// let status = subreaper::Supervisor::builder()
+///     .program("sleep")
+///     .args(["10"])
+///     .timeout(Duration::from_secs(1))
+///     .on_exit("/tmp/sleep.done")
+///     .spawn()
+///     .await?
+///     .wait()
+///     .await?;
+/// assert!(status.exit_reasons().timedout());
+/// # Ok(())
+/// # }
+/// ```
+pub struct Supervisor {
+    command: Command,
+    signals: BoxStream<'static, Signal>,
+    process: Process,
+    control: Option<Server>,
+}
+
+/// A single run of the child.
 struct Process {
-    reaped:    Channel,
-    child:     tokio::process::Child,
-    child_pid: u32,
-    flags:     Arc<ArcSwap<Flags>>,
+    exit:       Exit,
+    child:      tokio::process::Child,
+    child_pid:  u32,
+    relays:     Relays,
+    started_at: (SystemTime, Instant),
+    flags:      Arc<ArcSwap<Flags>>,
+    cgroup:     Option<Arc<Transient>>,
+    /// OOM kills in the cgroup before spawning the child, which may be restarted.
+    oom_kills:  u64,
+    /// The master of the pty, if the child runs under a pty.
+    pty:        Option<Pty>,
 }
 
+/// The exit status of the supervised child, and why it has exited.
 #[derive(Debug, Clone, thiserror::Error)]
 #[error("{exit_status}")]
-struct ExitStatus {
+pub struct ExitStatus {
     exit_status:  process::ExitStatus,
+    child_pid:    u32,
+    started_at:   (SystemTime, Instant),
+    /// Resource usage of the child, and its descendants waited for by the child.
+    rusage:       Rusage,
     exit_reasons: ExitReasons,
     flags:        Arc<ArcSwap<Flags>>,
 }
 
-#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
-struct ExitReasons {
+/// What happened to the child, and to its descendants.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExitReasons {
     timedout:       bool,
+    /// The child has been killed because it has written nothing for a while.
+    idle_timedout:  bool,
     child_signaled: Option<libc::c_int>,
-    iam_signaled:   Option<libc::c_int>,
+    /// The first signal that requested subreaper to stop the child.
+    iam_signaled:   Option<Forwarded>,
+    /// Descendants still alive after the drain grace period, and killed forcibly.
+    stragglers:     Vec<libc::pid_t>,
+    /// Orphans reparented to and reaped by subreaper, with their resource usage.
+    orphans:        Vec<(libc::pid_t, Rusage)>,
+    /// How many times the child has been restarted.
+    restarts:       u32,
+    /// Processes killed by the OOM killer in the cgroup, if any.
+    oom_kills:      u64,
 }
 
 #[derive(Debug, thiserror::Error)]
 #[error("{0}")]
-struct ExitStatusError(ExitStatus);
+struct ExitStatusError(Box<ExitStatus>);
 
 #[derive(Debug)]
 enum SpawnError {
     Io(io::Error),
     FoundErrFile(PathBuf),
+    WaitTimeout(Duration),
+    /// Failed to apply the attributes of the child before exec.
+    PreExec(Step, io::Error),
+}
+
+impl From<SpawnError> for io::Error {
+    fn from(err: SpawnError) -> io::Error {
+        match err {
+            SpawnError::Io(io_err) => io_err,
+            SpawnError::FoundErrFile(path) => io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("found an error file at {}", path.display()),
+            ),
+            SpawnError::WaitTimeout(timeout) => io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("not ready after {}", humantime::format_duration(timeout)),
+            ),
+            SpawnError::PreExec(Step::UserNamespace, io_err) => io::Error::new(
+                io_err.kind(),
+                format!(
+                    "user namespaces are unavailable ({io_err}), but --unshare-pid requires them \
+                     unless running as root"
+                ),
+            ),
+            SpawnError::PreExec(step, io_err) => {
+                io::Error::new(io_err.kind(), format!("{step} before exec: {io_err}"))
+            }
+        }
+    }
 }
 
 impl Flags {
@@ -344,175 +740,552 @@ impl Flags {
         I: IntoIterator<Item = T>,
         T: Into<OsString> + Clone,
     {
-        <Self as clap::Parser>::parse_from(args_os)
+        let mut flags = <Self as clap::Parser>::parse_from(args_os);
+        // As the init process, signals are forwarded to the process group of the child.
+        flags.kill.group |= flags.init.init;
+        if flags.init.init && !init::is_pid1() {
+            info!("not running as pid 1, so reaping orphans as a subreaper");
+        }
+        flags
     }
 
     fn command(self) -> Command {
-        let cmd = tokio::process::Command::new(&self.program);
+        let program = self.program.as_ref().expect("the program is required without a procfile");
+        let cmd = tokio::process::Command::new(program);
         let flags = Arc::new(ArcSwap::from_pointee(self));
-        Command { cmd, flags }
+        Command { cmd, flags, cgroup: None, setup: None, unshare: None, live: Default::default() }
     }
 }
 
 impl Command {
-    async fn spawn(mut self) -> io::Result<Process> {
+    /// Prepares the command, and spawns the child for the first time.
+    async fn spawn(mut self) -> io::Result<Supervisor> {
+        self.prepare().await?;
+        // Listens before spawning, not to be terminated by signals sent in the meantime.
+        let signals = Forward::listen(&self.flags.load().init.signals())?;
+        self.supervise(signals.boxed()).await
+    }
+
+    /// Waits for the dependencies to become ready, and configures the command.
+    async fn prepare(&mut self) -> io::Result<()> {
         let flags = self.flags.load();
-        wait_for(&flags.hook.wait_for).await.map_err(|err| match err {
-            SpawnError::Io(io_err) => io_err,
-            SpawnError::FoundErrFile(path) => io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("found an error file at {}", path.display()),
-            ),
-        })?;
+        flags.wait.ready().await?;
+
+        envs(&mut self.cmd, &flags.env).await?;
+        self.cmd.args(&flags.args[..]);
+        // Registered first, so that the remaining steps are taken in the init of the namespace.
+        if let Some(unshare) = flags.sandbox.unshare(!flags.terminal.pty)? {
+            let unshare = Arc::new(unshare);
+            let apply = unshare.clone();
+            // SAFETY: Unsharing the namespaces only makes async-signal-safe system calls.
+            unsafe {
+                self.cmd.pre_exec(move || apply.apply());
+            }
+            self.unshare = Some(unshare);
+        }
+        if flags.terminal.pty {
+            // The session leader is also the leader of a new process group.
+            // SAFETY: Only async-signal-safe system calls are made.
+            unsafe {
+                self.cmd.pre_exec(pty::login);
+            }
+        } else {
+            self.cmd
+                // Put the child into a new process group.
+                // A process group ID of 0 will use the process ID as the PGID.
+                .process_group(0)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+        }
+
+        if let Some(cgroup) = flags.cgroup.create().await? {
+            let procs = cgroup.procs_fd();
+            // SAFETY: Joining the cgroup only writes to the file opened in advance.
+            unsafe {
+                self.cmd.pre_exec(move || Transient::join(procs));
+            }
+            self.cgroup = Some(Arc::new(cgroup));
+        }
 
-        let reaped = Subreaper::subscribe();
-        let child = self
-            .cmd
-            .args(&flags.args[..])
-            // Put the child into a new process group.
-            // A process group ID of 0 will use the process ID as the PGID.
-            .process_group(0)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
-        let child_pid = child.id().expect("fetching the process id before polling should not fail");
-        Ok(Process { reaped, child, child_pid, flags: self.flags })
+        // Registered after joining the cgroup, which may require the privileges to be dropped.
+        if let Some(setup) = flags.attrs.setup()? {
+            let setup = Arc::new(setup);
+            let apply = setup.clone();
+            // SAFETY: Applying the attributes only makes async-signal-safe system calls.
+            unsafe {
+                self.cmd.pre_exec(move || apply.apply());
+            }
+            self.setup = Some(setup);
+        }
+        Ok(())
+    }
+
+    /// Spawns the child, which receives the given signals.
+    async fn supervise(mut self, signals: BoxStream<'static, Signal>) -> io::Result<Supervisor> {
+        let (control, stops) =
+            self.flags.load().control.listen(self.flags.clone(), self.live.clone())?;
+        let signals = stream::select(signals, stops).boxed();
+        let process = self.spawn_process(false).await?;
+        Ok(Supervisor { command: self, signals, process, control })
+    }
+
+    /// Spawns the child. Output files are appended to when restarting.
+    async fn spawn_process(&mut self, append: bool) -> io::Result<Process> {
+        let flags = self.flags.load();
+        let relays = flags.output.open(flags.name.as_deref(), append).await?;
+        let oom_kills = match &self.cgroup {
+            Some(cgroup) => cgroup.oom_kills().await,
+            None => 0,
+        };
+        let mut pty = None;
+        if flags.terminal.pty {
+            let (master, slave) = Pty::open()?;
+            let (stdin, stdout, stderr) = pty::stdio(&slave)?;
+            self.cmd.stdin(stdin).stdout(stdout).stderr(stderr);
+            pty = Some(master);
+        }
+        let spawned = Subreaper::spawn(&mut self.cmd, self.unshare.as_deref());
+        if pty.is_some() {
+            // Closes the slave, so that reading the master ends when the child closes it.
+            self.cmd.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+        }
+        let (child, child_pid, exit) = spawned.map_err(|err| {
+            let failed = self.unshare.as_ref().and_then(|unshare| unshare.failed());
+            match failed.or_else(|| self.setup.as_ref().and_then(|setup| setup.failed())) {
+                Some(step) => SpawnError::PreExec(step, err).into(),
+                None => err,
+            }
+        })?;
+        let started_at = (SystemTime::now(), Instant::now());
+        {
+            let mut live = self.live.lock().unwrap();
+            live.pid = Some(child_pid);
+            live.started_at = Some(started_at.1);
+            live.spawned += 1;
+        }
+        Ok(Process {
+            exit,
+            child,
+            child_pid,
+            relays,
+            started_at,
+            flags: self.flags.clone(),
+            cgroup: self.cgroup.clone(),
+            oom_kills,
+            pty,
+        })
     }
 }
 
-async fn wait_for(paths: &[PathBuf]) -> Result<(), SpawnError> {
-    let wait_files = paths.iter().map(|ok_file| async move {
-        let err_file = ok_file.with_extension("err");
+/// Configures the environment variables of the spawned process.
+async fn envs(cmd: &mut tokio::process::Command, env: &Env) -> io::Result<()> {
+    if env.clear {
+        cmd.env_clear();
+    }
 
-        loop {
-            tracing::trace!(wait_for = %ok_file.display());
+    for path in &env.files {
+        let vars = tokio::fs::read(path)
+            .await
+            .and_then(|source| parse_env_file(&source[..], std::env::vars().collect()))
+            .map_err(|err| {
+                io::Error::new(err.kind(), format!("env file {}: {}", path.display(), err))
+            })?;
+        cmd.envs(vars);
+    }
 
-            if err_file.try_exists().map_err(SpawnError::Io)? {
-                return Err(SpawnError::FoundErrFile(err_file));
+    for var in &env.vars {
+        match var.split_once('=') {
+            Some((key, val)) => {
+                cmd.env(key, val);
             }
-
-            if ok_file.try_exists().map_err(SpawnError::Io)? {
-                return Ok(());
+            None => {
+                if let Some(val) = std::env::var_os(var) {
+                    cmd.env(var, val);
+                }
             }
-
-            time::sleep(Duration::from_millis(1000)).await;
         }
-    });
+    }
 
-    future::try_join_all(wait_files).map_ok(|_| ()).await
+    Ok(())
 }
 
-impl Process {
-    async fn wait(self) -> io::Result<ExitStatus> {
-        let Process { mut reaped, mut child, child_pid, flags } = self;
+/// Parses `KEY=VALUE` pairs from the contents of an env file.
+fn parse_env_file(
+    source: &[u8],
+    vars: HashMap<String, String>,
+) -> io::Result<Vec<(String, String)>> {
+    let mut envs = Vec::new();
+    for line in shtok::Tokens::new(source.iter().copied(), Some(vars)) {
+        for token in line? {
+            let Some((key, val)) = token.split_once('=').filter(|(key, _)| !key.is_empty()) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("expected KEY=VALUE, but got '{token}'"),
+                ));
+            };
+            envs.push((key.to_owned(), val.to_owned()));
+        }
+    }
+    Ok(envs)
+}
 
-        // SIGTERM: stop monitored process
-        // SIGINT:  e.g., Ctrl-C at terminal
-        // SIGQUIT: e.g., Ctrl-\ at terminal
-        // SIGHUP:  e.g., terminal closed
-        let mut sigterm = signal(SignalKind::terminate())?;
-        let mut sigint = signal(SignalKind::interrupt())?;
+#[bon::bon]
+impl Supervisor {
+    /// Spawns the program, which is supervised until [`Supervisor::wait`] returns.
+    #[builder(finish_fn = spawn)]
+    pub async fn new(
+        /// The entrypoint of the child process.
+        #[builder(into)]
+        program: OsString,
+        /// The arguments passed to the program.
+        #[builder(default, with = |args: impl IntoIterator<Item = impl Into<OsString>>| {
+            args.into_iter().map(Into::into).collect()
+        })]
+        args: Vec<OsString>,
+        /// Environment variables set in addition to the inherited ones.
+        #[builder(default, with = |vars: impl IntoIterator<Item = (impl Into<OsString>, impl Into<OsString>)>| {
+            vars.into_iter().map(|(key, val)| (key.into(), val.into())).collect()
+        })]
+        envs: Vec<(OsString, OsString)>,
+        /// The working directory of the child.
+        #[builder(into)]
+        current_dir: Option<PathBuf>,
+        /// Kill the child if it is still running after the duration.
+        timeout: Option<Duration>,
+        /// Regard a timeout as a success.
+        #[builder(default, with = || true)]
+        timeout_is_ok: bool,
+        /// Kill the child if it writes nothing to stdout and stderr for the duration.
+        idle_timeout: Option<Duration>,
+        /// Send signals to the process group of the child, instead of the child only.
+        #[builder(default, with = || true)]
+        kill_group: bool,
+        /// Create an empty file after the child exits, or `PATH.err` on failure.
+        #[builder(into)]
+        on_exit: Option<PathBuf>,
+        /// Write a JSON report after the child exits.
+        #[builder(into)]
+        report: Option<PathBuf>,
+        /// How to emit stdout and stderr of the child. Written to those of the calling process
+        /// unchanged by default, since no tracing subscriber is installed to log them.
+        #[builder(default = OutputMode::Passthrough)]
+        output: OutputMode,
+        /// Forward the signals received by the calling process to the child.
+        ///
+        /// The default actions of the signals, e.g., terminating the process, are never restored.
+        #[builder(default, with = || true)]
+        forward_signals: bool,
+    ) -> io::Result<Supervisor> {
+        let mut flags = Flags {
+            timeout: Timeout {
+                kill_after: timeout,
+                is_ok: timeout_is_ok,
+                idle_timeout,
+                ..Timeout::default()
+            },
+            hook: Hook { on_exit, report },
+            program: Some(program),
+            args,
+            ..Flags::default()
+        };
+        flags.kill.group = kill_group;
+        flags.output.mode = output;
 
-        let mut reasons = ExitReasons::default();
+        let mut command = flags.command();
+        command.cmd.envs(envs);
+        if let Some(dir) = current_dir {
+            command.cmd.current_dir(dir);
+        }
+        if forward_signals {
+            return command.spawn().await;
+        }
+        command.prepare().await?;
+        command.supervise(stream::pending().boxed()).await
+    }
+
+    /// The process id of the child, which changes when restarted.
+    pub fn id(&self) -> u32 {
+        self.process.child_pid
+    }
+
+    /// Waits for the child and its descendants, and returns the exit status of the last run.
+    pub async fn wait(self) -> io::Result<ExitStatus> {
+        let flags = self.command.flags.clone();
+        let result = self.run().await;
+        finish(&flags.load().hook, result).await
+    }
+
+    /// Waits for the child, restarting it if needed, and returns the status of the last run.
+    async fn run(self) -> io::Result<ExitStatus> {
+        // The control socket is closed when returning.
+        let Supervisor { mut command, mut signals, mut process, control: _control } = self;
+        let flags = command.flags.clone();
+
+        let mut restarts = Restarts::default();
+        let mut status = loop {
+            let status = process.wait(&mut signals).await?;
+            {
+                let mut live = command.live.lock().unwrap();
+                live.pid = None;
+                live.orphans += status.exit_reasons.orphans.len();
+            }
+            let ran_for = status.started_at.1.elapsed();
+            let Some(delay) = flags.load().restart.next(&mut restarts, &status, ran_for) else {
+                break status;
+            };
+
+            info!(restarts = restarts.count, "restarting in {delay:?}: {status}");
+            if let Some(forwarded) = backoff(&mut signals, &flags, delay).await {
+                let mut status = status;
+                status.exit_reasons.iam_signaled = Some(forwarded);
+                break status;
+            }
+            process = command.spawn_process(true).await?;
+        };
+        status.exit_reasons.restarts = restarts.count;
+        Ok(status)
+    }
+}
 
-        let stdout = BufReader::new(child.stdout.take().unwrap());
-        let mut stdout = stdout.lines();
+/// Writes the report and the marker file after all children exit.
+async fn finish(hook: &Hook, result: io::Result<ExitStatus>) -> io::Result<ExitStatus> {
+    if let (Some(path), Ok(status)) = (hook.report.as_ref(), &result) {
+        Report::new(status).write(path).await?;
+    }
 
-        let stderr = BufReader::new(child.stderr.take().unwrap());
-        let mut stderr = stderr.lines();
+    on_exit(hook.on_exit.as_ref(), result).await
+}
 
-        let _r = tokio::task::spawn(async move {
-            loop {
-                tokio::select! {
-                    line = stdout.next_line() => handle_line(line),
-                    line = stderr.next_line() => handle_line(line),
+/// Sleeps before restarting, and returns the signal if subreaper is requested to stop meanwhile.
+async fn backoff(
+    signals: &mut BoxStream<'static, Signal>,
+    flags: &Arc<ArcSwap<Flags>>,
+    delay: Duration,
+) -> Option<Forwarded> {
+    let sleep = time::sleep(delay);
+    tokio::pin!(sleep);
+    loop {
+        tokio::select! {
+            Some(received) = signals.next() => {
+                // There is no child to relay other signals to.
+                if received == Signal::TERM || received == Signal::INT {
+                    return Some(flags.load().forward.translate(received));
                 }
+            },
+            _ = &mut sleep => return None,
+        }
+    }
+}
+
+impl Process {
+    async fn wait(self, signals: &mut BoxStream<'static, Signal>) -> io::Result<ExitStatus> {
+        // Keep the child until it exits, because tokio tries to reap a dropped child in background.
+        let Process {
+            mut exit,
+            mut child,
+            child_pid,
+            relays,
+            started_at,
+            flags,
+            cgroup,
+            oom_kills,
+            pty,
+        } = self;
+
+        let mut reasons = ExitReasons::default();
+
+        type Reader = Box<dyn AsyncRead + Send + Unpin>;
+        let (stdout, stderr): (Reader, Reader) = match &pty {
+            Some(pty) => (Box::new(pty.clone()), Box::new(tokio::io::empty())),
+            None => {
+                (Box::new(child.stdout.take().unwrap()), Box::new(child.stderr.take().unwrap()))
             }
-        });
+        };
+        let (activity, last_output) = watch::channel(Instant::now());
+        let relaying = tokio::task::spawn(relays.relay(stdout, stderr, activity));
+
+        let Timeout { kill_after, idle_timeout, warn_after, warn_signal, .. } =
+            flags.load().timeout;
+        let deadline = time::sleep(kill_after.unwrap_or_default());
+        tokio::pin!(deadline);
+        let idle = time::sleep(idle_timeout.unwrap_or_default());
+        tokio::pin!(idle);
+        let warning = time::sleep(warn_after.unwrap_or_default());
+        tokio::pin!(warning);
+        let mut warned = false;
+
+        // Escalating signals to stop the child, dropped when the child exits.
+        let policy = flags.load().kill.clone();
+        let mut escalation: Option<BoxFuture<'static, ()>> = None;
 
         let result = loop {
             tokio::select! {
-                _ = sigterm.recv() => {
-                    reasons.iam_signaled = reasons.iam_signaled.or(Some(libc::SIGTERM));
-                    kill(child_pid, Some(libc::SIGTERM)).await;
-                },
-                _ = sigint.recv() => {
-                    reasons.iam_signaled = reasons.iam_signaled.or(Some(libc::SIGINT));
-                    kill(child_pid, Some(libc::SIGINT)).await;
-                },
-                reaped = reaped.recv() => match reaped {
-                    Err(err) => {
-                        trace!("closed({}), lagged({})", err.closed(), err.lagged().unwrap_or(0));
+                Some(received) = signals.next() => {
+                    if let Some(pty) = pty.as_ref().filter(|_| received == Signal::WINCH) {
+                        // The pty sends SIGWINCH to its foreground process group if resized.
+                        pty.resize();
+                        continue;
                     }
-                    Ok((pid, exit_status)) => {
-                        trace!(reaped=pid, code=exit_status.code());
-                        if pid == child_pid as libc::pid_t {
-                            break Ok(to_exit_status(exit_status, reasons, &flags));
-                        }
+                    let forwarded = flags.load().forward.translate(received);
+                    trace!("forward {} as {}", forwarded.received, forwarded.sent);
+                    // Only SIGTERM and SIGINT request to stop the child. Others are just relayed,
+                    // e.g., SIGHUP may be used to reload configurations.
+                    if received == Signal::TERM || received == Signal::INT {
+                        reasons.iam_signaled = reasons.iam_signaled.or(Some(forwarded));
+                        stop(&policy, &mut escalation, child_pid, forwarded.sent);
+                    } else {
+                        policy.send(child_pid, forwarded.sent);
                     }
                 },
-                child_stat = wait_child(&mut child, flags.load()) => match child_stat {
-                    Err(err) => {
-                        // If the status of the child process has already been obtained by waitpid
-                        // within the reaper loop, wait_child will return the error “No child processes”.
-                        // Since this error is expected, we simply ignore it.
-                        trace!("got an error while waiting the child: {}", err.to_string());
+                reaped = &mut exit => {
+                    let reaped = reaped?;
+                    trace!(code=reaped.exit_status.code());
+                    break Ok(to_exit_status(reaped, child_pid, started_at, reasons, &flags));
+                },
+                _ = &mut deadline, if kill_after.is_some() && !reasons.timedout => {
+                    reasons.timedout = true;
+                    if escalation.is_none() {
+                        escalation = Some(policy.clone().escalate(child_pid, None).boxed());
                     }
-                    Ok(None) => {
-                        reasons.timedout = true;
-                        kill(child_pid, None).await;
+                },
+                _ = &mut idle, if idle_timeout.is_some() && !reasons.idle_timedout => {
+                    let idle_timeout = idle_timeout.unwrap();
+                    let last_output = *last_output.borrow();
+                    if last_output.elapsed() < idle_timeout {
+                        idle.as_mut().reset((last_output + idle_timeout).into());
+                        continue;
                     }
-                    Ok(Some(exit_status)) => {
-                        trace!(code=exit_status.code());
-                        break Ok(to_exit_status(exit_status, reasons, &flags));
+                    info!("no output for {}", humantime::format_duration(idle_timeout));
+                    reasons.idle_timedout = true;
+                    if escalation.is_none() {
+                        escalation = Some(policy.clone().escalate(child_pid, None).boxed());
                     }
                 },
+                _ = &mut warning, if warn_after.is_some() && !warned => {
+                    warned = true;
+                    info!("still running after {}, sending {warn_signal}", humantime::format_duration(warn_after.unwrap()));
+                    policy.send(child_pid, warn_signal);
+                },
+                _ = async { escalation.as_mut().unwrap().await }, if escalation.is_some() => {
+                    escalation = None;
+                },
             }
         };
+        drop(child);
 
-        // TODO: Reap all descendant processes here, to ensure there are no children left behind.
-        killpg(child_pid);
-        on_exit(flags.load().hook.on_exit.as_ref(), result).await
-    }
-}
+        let init = flags.load().init.init;
+        let mut stopping = match &result {
+            Ok(status) => {
+                let reasons = &status.exit_reasons;
+                reasons.timedout || reasons.idle_timedout || reasons.iam_signaled.is_some()
+            }
+            Err(_) => true,
+        };
+        if init && !stopping {
+            // As the init process, the descendants exit on their own, unless requested to stop.
+            // The exit status of the child is kept as is, since it has exited on its own.
+            let requested = async {
+                loop {
+                    tokio::select! {
+                        Some(received) = signals.next() => {
+                            if received == Signal::TERM || received == Signal::INT {
+                                return;
+                            }
+                            policy.send(child_pid, flags.load().forward.translate(received).sent);
+                        },
+                        _ = &mut deadline, if kill_after.is_some() => return,
+                        else => return std::future::pending().await,
+                    }
+                }
+            };
+            tokio::select! {
+                () = kill::wait_gone(child_pid, None) => {},
+                () = requested => stopping = true,
+            }
+        }
 
-fn handle_line(line: io::Result<Option<String>>) {
-    match line {
-        Err(err) => {
-            error!("got an error while reading stdout/stderr: {}", err.to_string());
+        // Reap all descendant processes here, to ensure there are no children left behind.
+        let mut stragglers = kill::drain(child_pid, &policy).await;
+        let mut oom_kills = oom_kills;
+        if let Some(cgroup) = &cgroup {
+            // Processes may have escaped from the process tree, but not from the cgroup.
+            for pid in cgroup.kill().await {
+                if !stragglers.contains(&pid) {
+                    stragglers.push(pid);
+                }
+            }
+            oom_kills = cgroup.oom_kills().await.saturating_sub(oom_kills);
+            if oom_kills > 0 {
+                info!(oom_kills, "processes killed by the OOM killer");
+            }
+        }
+        if !stragglers.is_empty() {
+            info!(stragglers = ?stragglers, "killed descendants left behind");
         }
-        Ok(None) => {
-            // info!("");
+        if init {
+            kill::wait_gone(child_pid, stopping.then_some(Signal::KILL)).await;
         }
-        Ok(Some(line)) => {
-            info!("{}", line);
+        let orphans = Subreaper::take_unclaimed();
+
+        // All writers are gone unless some escaped from the process group.
+        match time::timeout(RELAY_TIMEOUT, relaying).await {
+            Ok(Ok(Err(err))) => error!("got an error while relaying stdout/stderr: {err}"),
+            Ok(Err(err)) => error!("relaying stdout/stderr panicked: {err}"),
+            Err(_) => error!("stdout/stderr still open after {RELAY_TIMEOUT:?}"),
+            Ok(Ok(Ok(()))) => {}
         }
+
+        result.map(|mut status| {
+            status.exit_reasons.stragglers = stragglers;
+            status.exit_reasons.orphans = orphans;
+            status.exit_reasons.oom_kills = oom_kills;
+            let total = status.total_rusage();
+            info!(
+                user_time_us = total.user_time_us,
+                system_time_us = total.system_time_us,
+                max_rss_kb = total.max_rss_kb,
+                minor_faults = total.minor_faults,
+                major_faults = total.major_faults,
+                voluntary_csw = total.voluntary_csw,
+                involuntary_csw = total.involuntary_csw,
+                "resource usage of {} process(es)",
+                status.exit_reasons.orphans.len() + 1,
+            );
+            status
+        })
     }
 }
 
-/// Waits until the child exits or times out, and returns ExitStatus.
-/// For the case of timeout, Ok(None) will be returned.
-async fn wait_child(
-    child: &mut tokio::process::Child,
-    flags: arc_swap::Guard<Arc<Flags>>,
-) -> io::Result<Option<process::ExitStatus>> {
-    match flags.timeout.kill_after {
-        // Always some because no timeout given.
-        None => child.wait().await.map(Some),
-        Some(dur) => match time::timeout(dur, child.wait()).await {
-            Err(_elapsed) => Ok(None),
-            Ok(status) => status.map(Some),
-        },
+/// Forwards the signal to the child, and starts escalating if not yet.
+fn stop(
+    policy: &Kill,
+    escalation: &mut Option<BoxFuture<'static, ()>>,
+    child_pid: u32,
+    signal: Signal,
+) {
+    match escalation {
+        Some(_) => policy.send(child_pid, signal),
+        None => *escalation = Some(policy.clone().escalate(child_pid, Some(signal)).boxed()),
     }
 }
 
 fn to_exit_status(
-    exit_status: process::ExitStatus,
+    reaped: Reaped,
+    child_pid: u32,
+    started_at: (SystemTime, Instant),
     mut cause: ExitReasons,
     flags: &Arc<ArcSwap<Flags>>,
 ) -> ExitStatus {
+    let Reaped { exit_status, rusage } = reaped;
     cause.child_signaled = exit_status.signal().or(cause.child_signaled);
-    ExitStatus { exit_status, exit_reasons: cause, flags: Arc::clone(flags) }
+    ExitStatus {
+        exit_status,
+        child_pid,
+        started_at,
+        rusage,
+        exit_reasons: cause,
+        flags: Arc::clone(flags),
+    }
 }
 
 #[tracing::instrument]
@@ -542,53 +1315,225 @@ mod c {
     }
 }
 
-async fn kill(pid: u32, signal: Option<libc::c_int>) {
-    let gracefully = true;
-    let pid = pid as libc::pid_t;
+mod procfs {
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::{
+        fs,
+        io,
+        process,
+    };
+
+    /// Lists all descendants of the current process, including zombies not reaped yet.
+    pub(crate) fn descendants() -> io::Result<Vec<libc::pid_t>> {
+        check_pid_namespace()?;
+        let mut found = Vec::new();
+        let mut stack = children(process::id() as libc::pid_t)?;
+        while let Some(pid) = stack.pop() {
+            found.push(pid);
+            // The process may have gone in the meantime.
+            if let Ok(grandchildren) = children(pid) {
+                stack.extend(grandchildren);
+            }
+        }
+        Ok(found)
+    }
 
-    // Notify the spawned process to be terminated.
-    if gracefully {
-        let signal = signal.unwrap_or(libc::SIGTERM);
-        if let Err(err) = c::kill(pid, signal) {
-            trace!(pid = pid, "kill({}): {}", signal, err);
+    /// Lists the members of the process group and their descendants, including zombies.
+    pub(crate) fn group(pgid: libc::pid_t) -> io::Result<Vec<libc::pid_t>> {
+        check_pid_namespace()?;
+        let mut stack = Vec::new();
+        for entry in fs::read_dir("/proc")? {
+            let Some(pid) = entry?.file_name().to_str().and_then(|pid| pid.parse().ok()) else {
+                continue;
+            };
+            if self::pgid(pid) == Some(pgid) {
+                stack.push(pid);
+            }
+        }
+
+        let mut found = HashSet::new();
+        while let Some(pid) = stack.pop() {
+            // Descendants may have left the process group.
+            if found.insert(pid) {
+                stack.extend(children(pid).unwrap_or_default());
+            }
         }
-        time::sleep(Duration::from_millis(500)).await;
+        Ok(found.into_iter().collect())
     }
 
-    if let Err(err) = c::kill(pid, libc::SIGKILL) {
-        trace!(pid = pid, "kill: {}", err);
+    /// Pids in procfs mounted for another pid namespace refer to other processes, e.g., when
+    /// running as pid 1 of a new pid namespace without mounting procfs.
+    fn check_pid_namespace() -> io::Result<()> {
+        let self_pid = fs::read_link("/proc/self")?;
+        if self_pid.to_str() != Some(process::id().to_string().as_str()) {
+            return Err(io::Error::other("/proc is not mounted for the current pid namespace"));
+        }
+        Ok(())
     }
-}
 
-fn killpg(pid: u32) {
-    if let Err(err) = c::killpg(pid as libc::c_int, libc::SIGKILL) {
-        trace!(pid = pid, "killpg: {}", err);
+    /// Lists the children of the given process.
+    ///
+    /// Children are tracked per thread, so all of `/proc/<pid>/task/<tid>/children` are read.
+    fn children(pid: libc::pid_t) -> io::Result<Vec<libc::pid_t>> {
+        let mut pids = Vec::new();
+        for task in fs::read_dir(Path::new("/proc").join(pid.to_string()).join("task"))? {
+            let children = match fs::read_to_string(task?.path().join("children")) {
+                Ok(children) => children,
+                // The thread may have exited.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            pids.extend(
+                children.split_ascii_whitespace().filter_map(|pid| pid.parse::<libc::pid_t>().ok()),
+            );
+        }
+        Ok(pids)
+    }
+
+    /// Tests if the given process is a zombie, i.e., exited but not reaped yet.
+    pub(crate) fn is_zombie(pid: libc::pid_t) -> bool {
+        stat(pid, 0).as_deref() == Some("Z")
+    }
+
+    /// Returns the process group of the given process.
+    fn pgid(pid: libc::pid_t) -> Option<libc::pid_t> {
+        stat(pid, 2).and_then(|pgid| pgid.parse().ok())
+    }
+
+    /// Reads the nth field following the comm field in `/proc/<pid>/stat`, i.e., 0 is the state.
+    fn stat(pid: libc::pid_t, nth: usize) -> Option<String> {
+        let stat =
+            fs::read_to_string(Path::new("/proc").join(pid.to_string()).join("stat")).ok()?;
+        // The comm field is parenthesized, and it may contain spaces or parentheses.
+        let i = stat.rfind(')')?;
+        stat[i + 1..].split_ascii_whitespace().nth(nth).map(str::to_owned)
     }
 }
 
 impl ExitStatus {
+    /// The exit status of the child.
+    pub fn exit_status(&self) -> process::ExitStatus {
+        self.exit_status
+    }
+
+    /// The process id of the child.
+    pub fn id(&self) -> u32 {
+        self.child_pid
+    }
+
+    /// Why the child has exited.
+    pub fn exit_reasons(&self) -> &ExitReasons {
+        &self.exit_reasons
+    }
+
+    /// Whether the child has exited successfully, or has timed out with `timeout_is_ok`.
+    pub fn success(&self) -> bool {
+        self.exit_ok().is_ok()
+    }
+
+    /// Resource usage of the whole process tree, i.e., the child and the orphans.
+    fn total_rusage(&self) -> Rusage {
+        self.exit_reasons.orphans.iter().fold(self.rusage, |total, (_, rusage)| total + *rusage)
+    }
+
+    /// The exit code of subreaper, e.g., 124 on timeouts, or zero on success.
+    pub fn code(&self) -> u8 {
+        self.exit_ok().map_or_else(|err| err.code(), |()| 0)
+    }
+
     fn exit_ok(&self) -> Result<(), ExitStatusError> {
-        let exit_success = self.exit_status.success();
-        let timedout_but_ok = self.exit_reasons.timedout && self.flags.load().timeout.is_ok;
-        if exit_success || timedout_but_ok { Ok(()) } else { Err(ExitStatusError(self.clone())) }
+        let flags = self.flags.load();
+        let cause = self.cause();
+        let timedout_but_ok = cause == Cause::Timeout && flags.timeout.is_ok;
+        if timedout_but_ok || flags.exit_codes.is_success(flags.exit_codes.code(cause)) {
+            Ok(())
+        } else {
+            Err(ExitStatusError(Box::new(self.clone())))
+        }
     }
-}
 
-impl ExitStatusError {
-    fn exit_code(&self) -> ExitCode {
-        let ws = &self.0;
+    /// Why the child has exited, which determines the exit code.
+    fn cause(&self) -> Cause {
+        // The child may handle the signal sent because of timeout, or forwarded, and exit
+        // successfully.
+        if self.exit_status.success() {
+            return Cause::Exited(0);
+        }
 
-        if ws.exit_reasons.timedout {
-            return ExitCode::from(124);
+        let reasons = &self.exit_reasons;
+        if reasons.idle_timedout {
+            return Cause::IdleTimeout;
+        }
+        if reasons.timedout {
+            return Cause::Timeout;
         }
 
-        if let Some(s) = ws.exit_reasons.iam_signaled {
-            return ExitCode::from(128 + s as u8);
+        if let Some(s) = reasons.iam_signaled {
+            return Cause::Signaled(s.received);
         }
-        if let Some(s) = ws.exit_reasons.child_signaled {
-            return ExitCode::from(128 + s as u8);
+        if let Some(s) = reasons.child_signaled {
+            return Cause::Signaled(Signal(s));
         }
+        Cause::Exited(self.exit_status.code().map(|c| c as u8).unwrap_or(1))
+    }
+}
+
+impl ExitReasons {
+    /// Whether the child has been killed because of the timeout.
+    pub fn timedout(&self) -> bool {
+        self.timedout
+    }
+
+    /// Whether the child has been killed because of no output for the idle timeout.
+    pub fn idle_timedout(&self) -> bool {
+        self.idle_timedout
+    }
+
+    /// The signal which terminated the child.
+    pub fn child_signaled(&self) -> Option<libc::c_int> {
+        self.child_signaled
+    }
 
-        ws.exit_status.code().map(|c| ExitCode::from(c as u8)).unwrap_or(ExitCode::FAILURE)
+    /// The first signal received to stop the child.
+    pub fn iam_signaled(&self) -> Option<libc::c_int> {
+        self.iam_signaled.map(|forwarded| forwarded.received.0)
+    }
+
+    /// Descendants still alive after the drain grace period, and killed forcibly.
+    pub fn stragglers(&self) -> &[libc::pid_t] {
+        &self.stragglers
+    }
+
+    /// Orphans reparented to and reaped by subreaper.
+    ///
+    /// Always empty under [`Supervisor`], which does not make the process a subreaper.
+    pub fn orphans(&self) -> impl Iterator<Item = libc::pid_t> + '_ {
+        self.orphans.iter().map(|(pid, _)| *pid)
+    }
+
+    /// How many times the child has been restarted.
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// Processes killed by the OOM killer in the cgroup.
+    pub fn oom_kills(&self) -> u64 {
+        self.oom_kills
+    }
+}
+
+impl ExitStatusError {
+    fn exit_code(&self) -> ExitCode {
+        ExitCode::from(self.code())
+    }
+
+    fn code(&self) -> u8 {
+        let ws = &self.0;
+        // Zero may be excluded from the success codes, but exiting with zero means success.
+        match ws.flags.load().exit_codes.code(ws.cause()) {
+            0 => 1,
+            code => code,
+        }
     }
 }
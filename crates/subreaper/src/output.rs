@@ -0,0 +1,333 @@
+//! Relaying stdout and stderr of the child.
+
+use std::io;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::time::{
+    Instant,
+    SystemTime,
+};
+
+use tokio::fs;
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncSeekExt,
+    AsyncWrite,
+    AsyncWriteExt,
+};
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::fsutil;
+
+/// How to emit stdout and stderr of the child.
+#[derive(Debug, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct Output {
+    /// How to emit stdout and stderr of the child.
+    #[arg(long = "output", value_name = "MODE", value_enum, default_value_t = Mode::Log)]
+    pub(crate) mode: Mode,
+
+    /// Also write stdout of the child into the file as is.
+    #[arg(long = "stdout-file", value_name = "PATH")]
+    stdout_file: Option<PathBuf>,
+
+    /// Also write stderr of the child into the file as is.
+    #[arg(long = "stderr-file", value_name = "PATH")]
+    stderr_file: Option<PathBuf>,
+
+    /// Rotate the output files when they exceed the size in bytes.
+    #[arg(long = "output-max-size", value_name = "BYTES")]
+    max_size: Option<u64>,
+
+    /// The number of rotated output files to keep, i.e., `PATH.1`, `PATH.2` and so on.
+    #[arg(long = "output-max-files", value_name = "N", default_value_t = 5)]
+    max_files: usize,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            mode:        Mode::Log,
+            stdout_file: None,
+            stderr_file: None,
+            max_size:    None,
+            max_files:   5,
+        }
+    }
+}
+
+/// How to emit stdout and stderr of the child.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+    /// Log each line to stderr of subreaper.
+    Log,
+    /// Write stdout and stderr to those of subreaper unchanged.
+    Passthrough,
+    /// Prefix each line with a timestamp and the stream name.
+    Prefix,
+    /// Write each line as a JSON object to stdout of subreaper.
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Relays stdout and stderr of the child, opened before spawning the child.
+pub(crate) struct Relays {
+    mode:   Mode,
+    stdout: Relay,
+    stderr: Relay,
+}
+
+/// Relays one of the output streams of the child.
+struct Relay {
+    stream:  Stream,
+    /// The name of the procfile entry, prefixed to each line.
+    name:    Option<String>,
+    /// Bytes read after the last newline.
+    partial: Vec<u8>,
+    file:    Option<RotatingFile>,
+}
+
+/// A file rotated when its size exceeds the limit.
+struct RotatingFile {
+    path:      PathBuf,
+    file:      fs::File,
+    size:      u64,
+    max_size:  Option<u64>,
+    max_files: usize,
+}
+
+const BUF_SIZE: usize = 8 * 1024;
+
+impl Output {
+    /// Opens the output files, so that errors surface before spawning the child.
+    ///
+    /// The files are truncated unless appending, e.g., when the child is restarted.
+    pub(crate) async fn open(&self, name: Option<&str>, append: bool) -> io::Result<Relays> {
+        Ok(Relays {
+            mode:   self.mode,
+            stdout: Relay::new(Stream::Stdout, name, self.file(&self.stdout_file, append).await?),
+            stderr: Relay::new(Stream::Stderr, name, self.file(&self.stderr_file, append).await?),
+        })
+    }
+
+    async fn file(&self, path: &Option<PathBuf>, append: bool) -> io::Result<Option<RotatingFile>> {
+        match path {
+            None => Ok(None),
+            Some(path) => {
+                RotatingFile::open(path, append, self.max_size, self.max_files).await.map(Some)
+            }
+        }
+    }
+}
+
+impl Relays {
+    /// Relays stdout and stderr of the child until both of them are closed.
+    ///
+    /// The time of the last output is sent to `activity`.
+    pub(crate) async fn relay<O, E>(
+        self,
+        mut stdout: O,
+        mut stderr: E,
+        activity: watch::Sender<Instant>,
+    ) -> io::Result<()>
+    where
+        O: AsyncRead + Unpin,
+        E: AsyncRead + Unpin,
+    {
+        let Relays { mode, stdout: mut out, stderr: mut err } = self;
+        let mut console = (tokio::io::stdout(), tokio::io::stderr());
+
+        let (mut out_buf, mut err_buf) = ([0; BUF_SIZE], [0; BUF_SIZE]);
+        let (mut out_closed, mut err_closed) = (false, false);
+        while !(out_closed && err_closed) {
+            // Reading is cancel safe.
+            tokio::select! {
+                n = stdout.read(&mut out_buf), if !out_closed => {
+                    let n = n?;
+                    out_closed = n == 0;
+                    activity.send_replace(Instant::now());
+                    out.relay(mode, &mut console, &out_buf[..n]).await?;
+                }
+                n = stderr.read(&mut err_buf), if !err_closed => {
+                    let n = n?;
+                    err_closed = n == 0;
+                    activity.send_replace(Instant::now());
+                    err.relay(mode, &mut console, &err_buf[..n]).await?;
+                }
+            }
+        }
+
+        if let Some(file) = out.file.as_mut() {
+            file.file.flush().await?;
+        }
+        if let Some(file) = err.file.as_mut() {
+            file.file.flush().await?;
+        }
+        console.0.flush().await?;
+        console.1.flush().await
+    }
+}
+
+impl Relay {
+    fn new(stream: Stream, name: Option<&str>, file: Option<RotatingFile>) -> Self {
+        Relay { stream, name: name.map(str::to_owned), partial: Vec::new(), file }
+    }
+
+    /// Relays the bytes read from the stream. Empty bytes mean the end of the stream.
+    async fn relay<O, E>(
+        &mut self,
+        mode: Mode,
+        console: &mut (O, E),
+        bytes: &[u8],
+    ) -> io::Result<()>
+    where
+        O: AsyncWrite + Unpin,
+        E: AsyncWrite + Unpin,
+    {
+        if let Some(file) = self.file.as_mut() {
+            file.write(bytes).await?;
+        }
+
+        if mode == Mode::Passthrough {
+            return match self.stream {
+                Stream::Stdout => console.0.write_all(bytes).await,
+                Stream::Stderr => console.1.write_all(bytes).await,
+            };
+        }
+
+        self.partial.extend_from_slice(bytes);
+        let mut lines = Vec::new();
+        while let Some(i) = self.partial.iter().position(|&b| b == b'\n') {
+            lines.push(self.partial.drain(..=i).collect::<Vec<_>>());
+        }
+        // Flushes the partial line at the end of the stream.
+        if bytes.is_empty() && !self.partial.is_empty() {
+            lines.push(std::mem::take(&mut self.partial));
+        }
+
+        for line in lines {
+            self.line(mode, console, &line).await?;
+        }
+        Ok(())
+    }
+
+    /// Emits a line, which ends with a newline unless it is the partial final line.
+    ///
+    /// The partial line is emitted without a newline, or marked as `"partial": true` in JSON.
+    async fn line<O, E>(&self, mode: Mode, console: &mut (O, E), line: &[u8]) -> io::Result<()>
+    where
+        O: AsyncWrite + Unpin,
+        E: AsyncWrite + Unpin,
+    {
+        let content = line.strip_suffix(b"\n");
+        let partial = content.is_none();
+        let content = content.unwrap_or(line);
+        let ts = humantime::format_rfc3339_micros(SystemTime::now());
+        let stream = self.stream.name();
+        match mode {
+            Mode::Log => {
+                match &self.name {
+                    Some(name) => info!(stream, "{name}: {}", String::from_utf8_lossy(content)),
+                    None => info!(stream, "{}", String::from_utf8_lossy(content)),
+                }
+                Ok(())
+            }
+            Mode::Prefix => {
+                let mut buf = match &self.name {
+                    Some(name) => format!("{ts} {name} {stream}: ").into_bytes(),
+                    None => format!("{ts} {stream}: ").into_bytes(),
+                };
+                buf.extend_from_slice(line);
+                match self.stream {
+                    Stream::Stdout => console.0.write_all(&buf).await,
+                    Stream::Stderr => console.1.write_all(&buf).await,
+                }
+            }
+            Mode::Json => {
+                let mut json = serde_json::json!({
+                    "ts": ts.to_string(),
+                    "stream": stream,
+                    "line": String::from_utf8_lossy(content),
+                });
+                if let Some(name) = &self.name {
+                    json["name"] = serde_json::json!(name);
+                }
+                // The final line without a newline, e.g., truncated output.
+                if partial {
+                    json["partial"] = serde_json::json!(true);
+                }
+                // Keeps the original bytes if they are not valid UTF-8.
+                if std::str::from_utf8(content).is_err() {
+                    json["bytes"] = serde_json::json!(content);
+                }
+                let mut buf = serde_json::to_vec(&json).map_err(io::Error::other)?;
+                buf.push(b'\n');
+                console.0.write_all(&buf).await
+            }
+            Mode::Passthrough => unreachable!("lines are not split in passthrough mode"),
+        }
+    }
+}
+
+impl Stream {
+    fn name(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+impl RotatingFile {
+    async fn open(
+        path: &Path,
+        append: bool,
+        max_size: Option<u64>,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        let mut file = fs::File::from_std(fsutil::create_file(path, !append).await?);
+        let size = file.seek(io::SeekFrom::End(0)).await?;
+        Ok(RotatingFile { path: path.to_path_buf(), file, size, max_size, max_files })
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.max_size.is_some_and(|max| self.size > 0 && self.size + bytes.len() as u64 > max) {
+            self.rotate().await?;
+        }
+        self.file.write_all(bytes).await?;
+        self.size += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Renames `PATH.{n}` to `PATH.{n+1}`, and `PATH` to `PATH.1`, then recreates `PATH`.
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{n}"));
+            PathBuf::from(path)
+        };
+
+        if self.max_files > 0 {
+            for n in (1..self.max_files).rev() {
+                match fs::rename(rotated(n), rotated(n + 1)).await {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated(1)).await?;
+        }
+
+        self.file = fs::File::from_std(fsutil::create_file(&self.path, true).await?);
+        self.size = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+//! Running all entries of a procfile under the same subreaper.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use futures::stream::FuturesUnordered;
+use tracing::info;
+
+use crate::signal::{
+    Forward,
+    Signal,
+};
+use crate::{
+    ExitStatus,
+    Flags,
+    finish,
+};
+
+/// Runs every entry of a procfile instead of a single program.
+#[derive(Debug, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct Procfile {
+    /// Run every entry of the procfile, each in its own process group.
+    ///
+    /// Each line is a command line, interpreted in the same way as env files, optionally
+    /// prefixed by `name:` and `KEY=value` assignments exported to the entry.
+    /// Other flags, e.g., timeouts and restart policies, apply to each entry.
+    #[arg(
+        long = "procfile",
+        id = "procfile",
+        value_name = "PATH",
+        conflicts_with_all = ["program", "stdout_file", "stderr_file"],
+    )]
+    pub(crate) path: Option<PathBuf>,
+
+    /// What to do when one of the entries exits.
+    #[arg(
+        long = "procfile-policy",
+        id = "procfile_policy",
+        value_name = "POLICY",
+        value_enum,
+        default_value_t = Policy::AnyExitStopsAll,
+    )]
+    policy: Policy,
+}
+
+impl Default for Procfile {
+    fn default() -> Self {
+        Procfile { path: None, policy: Policy::AnyExitStopsAll }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Policy {
+    /// Stop all the other entries when one of them exits.
+    AnyExitStopsAll,
+    /// Wait for all entries to exit.
+    WaitForAll,
+}
+
+/// An entry exited, and whether it has been stopped because another entry exited.
+struct Exited {
+    result:  io::Result<ExitStatus>,
+    stopped: bool,
+}
+
+impl Procfile {
+    /// Spawns all entries, and waits for them according to the policy.
+    ///
+    /// Exits with the status of the first failed entry, or of the first exited entry if all
+    /// succeeded.
+    pub(crate) async fn run(self, flags: Flags) -> io::Result<ExitStatus> {
+        let path = self.path.as_ref().expect("running a procfile requires its path");
+        let mut commands = Vec::new();
+        for (name, entry) in entries(path).await? {
+            let mut flags = flags.clone();
+            let mut argv = entry.argv.into_iter().map(OsString::from);
+            flags.program = argv.next();
+            flags.args = argv.collect();
+            flags.name = Some(name);
+            flags.env.vars.extend(entry.env.into_iter().map(|(k, v)| format!("{k}={v}")));
+
+            let mut command = flags.command();
+            command.prepare().await?;
+            commands.push(command);
+        }
+
+        // Listens before spawning, not to be terminated by signals sent in the meantime.
+        let mut signals = Forward::listen(&flags.init.signals())?;
+
+        // Each entry receives signals through its own channel.
+        let mut senders = Vec::new();
+        let mut running = FuturesUnordered::new();
+        let mut spawn_error = None;
+        for (i, command) in commands.into_iter().enumerate() {
+            let (tx, rx) = mpsc::unbounded();
+            match command.supervise(rx.boxed()).await {
+                Ok(supervisor) => {
+                    senders.push(Some(tx));
+                    running.push(supervisor.run().map(move |result| (i, result)));
+                }
+                Err(err) => {
+                    spawn_error = Some(err);
+                    break;
+                }
+            }
+        }
+
+        let mut stopping = spawn_error.is_some();
+        if stopping {
+            stop_all(&senders);
+        }
+
+        let mut exited = Vec::new();
+        while !running.is_empty() {
+            tokio::select! {
+                Some(received) = signals.next() => {
+                    for tx in senders.iter().flatten() {
+                        let _ = tx.unbounded_send(received);
+                    }
+                },
+                Some((i, result)) = running.next() => {
+                    if let Ok(status) = &result {
+                        info!(entry = status.flags.load().name, "exited: {status}");
+                    }
+                    exited.push(Exited { result, stopped: stopping });
+                    senders[i] = None;
+                    if self.policy == Policy::AnyExitStopsAll && !stopping {
+                        stopping = true;
+                        stop_all(&senders);
+                    }
+                },
+            }
+        }
+
+        let result = match spawn_error {
+            Some(err) => Err(err),
+            None => first_failure(exited),
+        };
+        finish(&flags.hook, result).await
+    }
+}
+
+/// Reads the procfile, and names each entry after its `name:` prefix or its program.
+///
+/// Entries with the same name are numbered, e.g., `worker`, `worker.2`.
+async fn entries(path: &Path) -> io::Result<Vec<(String, shtok::Entry)>> {
+    let with_path = |err: io::Error| {
+        io::Error::new(err.kind(), format!("procfile {}: {}", path.display(), err))
+    };
+    let source = tokio::fs::read(path).await.map_err(with_path)?;
+    let entries = shtok::parse(&source[..], Some(std::env::vars().collect()))
+        .map_err(|err| with_path(err.into()))?;
+    if entries.is_empty() {
+        return Err(with_path(io::Error::new(io::ErrorKind::InvalidData, "no entries")));
+    }
+
+    let mut seen = HashMap::new();
+    entries
+        .into_iter()
+        .map(|entry| {
+            // Lines with only a name or assignments are allowed in procrc, but run nothing.
+            let Some(program) = entry.argv.first() else {
+                let err = format!("no command to run at line {}", entry.line);
+                return Err(with_path(io::Error::new(io::ErrorKind::InvalidData, err)));
+            };
+            let name = match &entry.name {
+                Some(name) => name.clone(),
+                None => {
+                    let program = Path::new(program);
+                    program.file_name().unwrap_or(program.as_os_str()).to_string_lossy().into()
+                }
+            };
+            let n = seen.entry(name.clone()).and_modify(|n| *n += 1).or_insert(1);
+            let name = if *n == 1 { name } else { format!("{name}.{n}") };
+            Ok((name, entry))
+        })
+        .collect()
+}
+
+/// Requests all running entries to stop, as if subreaper received SIGTERM.
+fn stop_all(senders: &[Option<mpsc::UnboundedSender<Signal>>]) {
+    for tx in senders.iter().flatten() {
+        let _ = tx.unbounded_send(Signal::TERM);
+    }
+}
+
+/// Picks the first failure, ignoring entries stopped because others exited.
+fn first_failure(exited: Vec<Exited>) -> io::Result<ExitStatus> {
+    let failed = |exited: &Exited| match &exited.result {
+        Err(_) => true,
+        Ok(status) => status.exit_ok().is_err() && !exited.stopped,
+    };
+    let i = exited.iter().position(failed).unwrap_or(0);
+    exited.into_iter().nth(i).expect("at least one entry exited").result
+}
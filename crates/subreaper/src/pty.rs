@@ -0,0 +1,144 @@
+//! Running the child under a pseudo-terminal.
+
+use std::ffi::CStr;
+use std::io;
+use std::os::fd::{
+    AsRawFd,
+    FromRawFd,
+    OwnedFd,
+};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::task::{
+    Context,
+    Poll,
+    ready,
+};
+
+use tokio::io::unix::AsyncFd;
+use tokio::io::{
+    AsyncRead,
+    ReadBuf,
+};
+
+/// Whether the child runs under a pseudo-terminal.
+#[derive(Debug, Default, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct Terminal {
+    /// Run the child under a pseudo-terminal, instead of connecting its output to pipes.
+    ///
+    /// The child becomes a session leader with the pty as its controlling terminal, and its
+    /// stdout and stderr are relayed as stdout. The window size follows the terminal of subreaper.
+    #[arg(long = "pty")]
+    pub(crate) pty: bool,
+}
+
+/// The master side of a pty, read to relay the output of the child.
+#[derive(Clone)]
+pub(crate) struct Pty {
+    master: Arc<AsyncFd<OwnedFd>>,
+}
+
+impl Pty {
+    /// Opens a pty pair, and returns the master with the slave to be the stdio of the child.
+    ///
+    /// The window size is copied from the terminal of subreaper, if any.
+    pub(crate) fn open() -> io::Result<(Pty, OwnedFd)> {
+        let flags = libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC;
+        let master = unsafe { libc::posix_openpt(flags) };
+        if master < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { OwnedFd::from_raw_fd(master) };
+        check(unsafe { libc::grantpt(master.as_raw_fd()) })?;
+        check(unsafe { libc::unlockpt(master.as_raw_fd()) })?;
+
+        let mut name = [0 as libc::c_char; 64];
+        let ret = unsafe { libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+        let slave = unsafe { libc::open(name.as_ptr(), flags) };
+        if slave < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let slave = unsafe { OwnedFd::from_raw_fd(slave) };
+
+        // Newlines are not translated into CRLF, so that lines are relayed as if from a pipe.
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        check(unsafe { libc::tcgetattr(slave.as_raw_fd(), &mut termios) })?;
+        termios.c_oflag &= !libc::OPOST;
+        check(unsafe { libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) })?;
+
+        let nonblocking = unsafe {
+            let flags = libc::fcntl(master.as_raw_fd(), libc::F_GETFL);
+            libc::fcntl(master.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK)
+        };
+        check(nonblocking)?;
+
+        let pty = Pty { master: Arc::new(AsyncFd::new(master)?) };
+        pty.resize();
+        Ok((pty, slave))
+    }
+
+    /// Copies the window size of the terminal of subreaper, if any.
+    ///
+    /// The foreground process group of the pty receives SIGWINCH if the size has changed.
+    pub(crate) fn resize(&self) {
+        let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+        let found = (0..=2).any(|fd| unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } == 0);
+        if found {
+            unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &size) };
+        }
+    }
+}
+
+/// Makes the slave the stdio of the child.
+pub(crate) fn stdio(slave: &OwnedFd) -> io::Result<(Stdio, Stdio, Stdio)> {
+    Ok((slave.try_clone()?.into(), slave.try_clone()?.into(), slave.try_clone()?.into()))
+}
+
+/// Makes the calling process, i.e., the child after fork, a session leader with the pty as its
+/// controlling terminal. The child also becomes the leader of the foreground process group.
+pub(crate) fn login() -> io::Result<()> {
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    check(unsafe { libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY, 0) })
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+impl AsyncRead for Pty {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.master.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            let read = guard.try_io(|master| {
+                let n = unsafe {
+                    libc::read(master.as_raw_fd(), unfilled.as_mut_ptr().cast(), unfilled.len())
+                };
+                if n < 0 { Err(io::Error::last_os_error()) } else { Ok(n as usize) }
+            });
+            match read {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                // All of the slaves have been closed, which is the end of the output.
+                Ok(Err(err)) if err.raw_os_error() == Some(libc::EIO) => {
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! A structured report written after the child exits.
+
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::rusage::Rusage;
+use crate::signal::Signal;
+use crate::{
+    ExitStatus,
+    fsutil,
+};
+
+/// The JSON document written by `--report`.
+///
+/// If the child has been restarted, the report describes the last run.
+#[derive(Debug, Serialize)]
+pub(crate) struct Report {
+    /// The program and its arguments.
+    command:       Vec<String>,
+    pid:           u32,
+    pgid:          u32,
+    /// RFC 3339 timestamps.
+    started_at:    String,
+    ended_at:      String,
+    /// Wall-clock duration in milliseconds.
+    duration_ms:   u128,
+    /// The exit code of subreaper.
+    exit_code:     u8,
+    /// How the child has exited, e.g., `timeout` or `signal:SIGSEGV`, mapped to the exit code.
+    cause:         String,
+    /// The exit status of the child, either of which is present.
+    child:         Child,
+    timedout:      bool,
+    idle_timedout: bool,
+    /// The first signal that requested subreaper to stop the child.
+    signaled:      Option<Signaled>,
+    stragglers:    Vec<libc::pid_t>,
+    orphans:       Vec<libc::pid_t>,
+    restarts:      u32,
+    oom_kills:     u64,
+    rusage:        Rusages,
+}
+
+#[derive(Debug, Serialize)]
+struct Rusages {
+    child:   Rusage,
+    orphans: Vec<Orphan>,
+    /// The sum of the above, but `max_rss_kb` is the maximum.
+    total:   Rusage,
+}
+
+#[derive(Debug, Serialize)]
+struct Orphan {
+    pid:    libc::pid_t,
+    #[serde(flatten)]
+    rusage: Rusage,
+}
+
+#[derive(Debug, Serialize)]
+struct Child {
+    code:   Option<i32>,
+    signal: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Signaled {
+    received: String,
+    sent:     String,
+}
+
+impl Report {
+    pub(crate) fn new(status: &ExitStatus) -> Report {
+        let flags = status.flags.load();
+        let (pid, started_at) = (status.child_pid, status.started_at);
+        let reasons = &status.exit_reasons;
+        Report {
+            command: flags
+                .program
+                .iter()
+                .chain(&flags.args)
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            pid,
+            // The child is put into a new process group.
+            pgid: pid,
+            started_at: humantime::format_rfc3339_millis(started_at.0).to_string(),
+            ended_at: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            duration_ms: started_at.1.elapsed().as_millis(),
+            exit_code: status.code(),
+            cause: status.cause().to_string(),
+            child: Child {
+                code:   status.exit_status.code(),
+                signal: status.exit_status.signal().map(|s| Signal(s).to_string()),
+            },
+            timedout: reasons.timedout,
+            idle_timedout: reasons.idle_timedout,
+            signaled: reasons.iam_signaled.map(|forwarded| Signaled {
+                received: forwarded.received.to_string(),
+                sent:     forwarded.sent.to_string(),
+            }),
+            stragglers: reasons.stragglers.clone(),
+            orphans: reasons.orphans.iter().map(|(pid, _)| *pid).collect(),
+            restarts: reasons.restarts,
+            oom_kills: reasons.oom_kills,
+            rusage: Rusages {
+                child:   status.rusage,
+                orphans: reasons
+                    .orphans
+                    .iter()
+                    .map(|&(pid, rusage)| Orphan { pid, rusage })
+                    .collect(),
+                total:   status.total_rusage(),
+            },
+        }
+    }
+
+    pub(crate) async fn write(&self, path: &Path) -> io::Result<()> {
+        let file = fsutil::create_file(path, true).await?;
+        serde_json::to_writer_pretty(file, self).map_err(io::Error::other)
+    }
+}
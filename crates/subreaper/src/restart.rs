@@ -0,0 +1,127 @@
+//! Restarting the child after it exits.
+
+use std::collections::VecDeque;
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use tracing::info;
+
+use crate::ExitStatus;
+
+/// When and how often to restart the child.
+#[derive(Debug, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct Restart {
+    /// Restart the child after it exits, unless subreaper is requested to stop.
+    #[arg(long = "restart", value_name = "POLICY", value_enum, default_value_t = Policy::No)]
+    policy: Policy,
+
+    /// Give up restarting once the child has been restarted this many times within the window.
+    #[arg(long = "max-restarts", value_name = "N")]
+    max_restarts: Option<usize>,
+
+    /// The sliding window to count restarts within. All restarts are counted if not given.
+    #[arg(
+        long = "restart-window",
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration,
+    )]
+    window: Option<Duration>,
+
+    /// The delay before restarting, doubled on each consecutive restart.
+    ///
+    /// The delay is randomized between its half and itself, not to restart in lockstep.
+    #[arg(
+        long = "restart-delay",
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration,
+        default_value = "100ms",
+    )]
+    delay: Duration,
+
+    /// The upper bound of the delay. The delay is reset if the child runs longer than this.
+    #[arg(
+        long = "restart-max-delay",
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration,
+        default_value = "30s",
+    )]
+    max_delay: Duration,
+}
+
+impl Default for Restart {
+    fn default() -> Self {
+        Restart {
+            policy:       Policy::No,
+            max_restarts: None,
+            window:       None,
+            delay:        Duration::from_millis(100),
+            max_delay:    Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Policy {
+    /// Never restart the child.
+    No,
+    /// Restart the child if it exits unsuccessfully, including timeouts.
+    OnFailure,
+    /// Restart the child whenever it exits.
+    Always,
+}
+
+/// Restarts made so far.
+#[derive(Debug, Default)]
+pub(crate) struct Restarts {
+    /// When the recent restarts were made, within the window.
+    history:          VecDeque<Instant>,
+    /// Restarts since the child last ran longer than the maximum delay.
+    consecutive:      u32,
+    /// Restarts in total.
+    pub(crate) count: u32,
+}
+
+impl Restart {
+    /// Returns the delay before restarting the child, or None if the child should not be restarted.
+    pub(crate) fn next(
+        &self,
+        restarts: &mut Restarts,
+        status: &ExitStatus,
+        ran_for: Duration,
+    ) -> Option<Duration> {
+        let restart = match self.policy {
+            Policy::No => false,
+            Policy::OnFailure => status.exit_ok().is_err(),
+            Policy::Always => true,
+        };
+        // Subreaper itself has been requested to stop.
+        if !restart || status.exit_reasons.iam_signaled.is_some() {
+            return None;
+        }
+
+        let now = Instant::now();
+        if let Some(window) = self.window {
+            while restarts.history.front().is_some_and(|&at| now.duration_since(at) > window) {
+                restarts.history.pop_front();
+            }
+        }
+        if self.max_restarts.is_some_and(|max| restarts.history.len() >= max) {
+            info!(restarts = restarts.count, "gave up restarting");
+            return None;
+        }
+
+        if ran_for >= self.max_delay {
+            restarts.consecutive = 0;
+        }
+        let delay = self.delay.saturating_mul(2u32.saturating_pow(restarts.consecutive));
+        let delay = delay.min(self.max_delay);
+        let delay = delay.mul_f64(rand::random_range(0.5..=1.0));
+
+        restarts.history.push_back(now);
+        restarts.consecutive += 1;
+        restarts.count += 1;
+        Some(delay)
+    }
+}
@@ -0,0 +1,56 @@
+//! Resource usage of the reaped processes.
+
+use std::ops::Add;
+
+use serde::Serialize;
+
+/// Resource usage, a subset of `struct rusage` filled by `wait4`.
+///
+/// Includes the resources used by the descendants which have been waited for.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Rusage {
+    /// User CPU time in microseconds.
+    pub(crate) user_time_us:    u64,
+    /// System CPU time in microseconds.
+    pub(crate) system_time_us:  u64,
+    /// Maximum resident set size in kilobytes.
+    pub(crate) max_rss_kb:      u64,
+    /// Page faults serviced without any I/O.
+    pub(crate) minor_faults:    u64,
+    /// Page faults serviced that required I/O.
+    pub(crate) major_faults:    u64,
+    pub(crate) voluntary_csw:   u64,
+    pub(crate) involuntary_csw: u64,
+}
+
+impl From<libc::rusage> for Rusage {
+    fn from(usage: libc::rusage) -> Self {
+        let us = |tv: libc::timeval| tv.tv_sec as u64 * 1_000_000 + tv.tv_usec as u64;
+        Rusage {
+            user_time_us:    us(usage.ru_utime),
+            system_time_us:  us(usage.ru_stime),
+            max_rss_kb:      usage.ru_maxrss as u64,
+            minor_faults:    usage.ru_minflt as u64,
+            major_faults:    usage.ru_majflt as u64,
+            voluntary_csw:   usage.ru_nvcsw as u64,
+            involuntary_csw: usage.ru_nivcsw as u64,
+        }
+    }
+}
+
+/// Sums up resource usages, but takes the maximum of the resident set sizes.
+impl Add for Rusage {
+    type Output = Rusage;
+
+    fn add(self, rhs: Rusage) -> Rusage {
+        Rusage {
+            user_time_us:    self.user_time_us + rhs.user_time_us,
+            system_time_us:  self.system_time_us + rhs.system_time_us,
+            max_rss_kb:      self.max_rss_kb.max(rhs.max_rss_kb),
+            minor_faults:    self.minor_faults + rhs.minor_faults,
+            major_faults:    self.major_faults + rhs.major_faults,
+            voluntary_csw:   self.voluntary_csw + rhs.voluntary_csw,
+            involuntary_csw: self.involuntary_csw + rhs.involuntary_csw,
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Running the child as the init of a new pid namespace.
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{
+    AsRawFd,
+    FromRawFd,
+    OwnedFd,
+};
+
+use crate::attrs::{
+    Step,
+    write_file,
+};
+
+/// Namespaces the child is isolated in.
+#[derive(Debug, Default, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct Sandbox {
+    /// Run the child as pid 1 of a new pid namespace, which is still a child of subreaper.
+    ///
+    /// When the child exits, the kernel kills all of its descendants, so none of them is left
+    /// behind. As pid 1, the child receives only the signals it has a handler for, and SIGKILL.
+    ///
+    /// Unless running as root, a user namespace mapping the current user to root is created as
+    /// well, which requires unprivileged user namespaces to be enabled.
+    #[arg(long = "unshare-pid")]
+    unshare_pid: bool,
+
+    /// Also run the child in a new mount namespace, with a fresh /proc of the pid namespace.
+    #[arg(long = "unshare-mount", requires = "unshare_pid")]
+    unshare_mount: bool,
+}
+
+/// The namespaces resolved before fork, so that unsharing them only makes system calls.
+pub(crate) struct Unshare {
+    /// The uid and gid maps of the user namespace, if not running as root.
+    user_ns:   Option<(CString, CString)>,
+    mount:     bool,
+    /// Whether the init becomes the leader of a new process group.
+    new_group: bool,
+    /// The child writes the pid of the init, or the negated failed step into the pipe.
+    messages:  (OwnedFd, OwnedFd),
+}
+
+impl Sandbox {
+    /// Resolves the namespaces, or returns None if the child is not isolated.
+    pub(crate) fn unshare(&self, new_group: bool) -> io::Result<Option<Unshare>> {
+        if !self.unshare_pid {
+            return Ok(None);
+        }
+
+        let user_ns = match unsafe { libc::geteuid() } {
+            0 => None,
+            uid => {
+                let gid = unsafe { libc::getegid() };
+                let uid_map = CString::new(format!("0 {uid} 1")).unwrap();
+                let gid_map = CString::new(format!("0 {gid} 1")).unwrap();
+                Some((uid_map, gid_map))
+            }
+        };
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let messages = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        Ok(Some(Unshare { user_ns, mount: self.unshare_mount, new_group, messages }))
+    }
+}
+
+impl Unshare {
+    /// Forks the init of a new pid namespace from the calling process, i.e., the child after fork.
+    ///
+    /// The calling process exits after writing the pid of the init, which is a sibling of it
+    /// thanks to CLONE_PARENT. This returns in the init, which goes on to exec the program.
+    /// Only async-signal-safe functions are called here.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        let mut flags = 0;
+        if self.user_ns.is_some() {
+            flags |= libc::CLONE_NEWUSER;
+        }
+        if self.mount {
+            flags |= libc::CLONE_NEWNS;
+        }
+        if flags != 0 && unsafe { libc::unshare(flags) } != 0 {
+            let step =
+                if self.user_ns.is_some() { Step::UserNamespace } else { Step::MountNamespace };
+            return self.fail(step);
+        }
+        if let Some((uid_map, gid_map)) = &self.user_ns {
+            let mapped = unsafe {
+                write_file(c"/proc/self/setgroups", c"deny") == 0
+                    && write_file(c"/proc/self/uid_map", uid_map) == 0
+                    && write_file(c"/proc/self/gid_map", gid_map) == 0
+            };
+            if !mapped {
+                return self.fail(Step::UserNamespace);
+            }
+        }
+
+        let flags = libc::CLONE_PARENT | libc::CLONE_NEWPID | libc::SIGCHLD;
+        match unsafe { libc::syscall(libc::SYS_clone, flags, 0, 0, 0, 0) } {
+            -1 => self.fail(Step::PidNamespace),
+            0 => self.init(),
+            pid => {
+                self.write(pid as i32);
+                unsafe { libc::_exit(0) }
+            }
+        }
+    }
+
+    /// Prepares the init before the remaining steps and exec.
+    fn init(&self) -> io::Result<()> {
+        if self.new_group && unsafe { libc::setpgid(0, 0) } != 0 {
+            return self.fail(Step::PidNamespace);
+        }
+        if self.mount {
+            // Not to propagate the mount of /proc back to the parent namespace.
+            let private = libc::MS_REC | libc::MS_PRIVATE;
+            let proc = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+            let mounted = unsafe {
+                let null = std::ptr::null();
+                libc::mount(null, c"/".as_ptr(), null, private, null.cast()) == 0
+                    && libc::mount(
+                        c"proc".as_ptr(),
+                        c"/proc".as_ptr(),
+                        c"proc".as_ptr(),
+                        proc,
+                        null.cast(),
+                    ) == 0
+            };
+            if !mounted {
+                return self.fail(Step::MountProc);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports the failed step, and returns the error of it.
+    fn fail(&self, step: Step) -> io::Result<()> {
+        let err = io::Error::last_os_error();
+        self.write(-(step as i32));
+        Err(err)
+    }
+
+    fn write(&self, message: i32) {
+        unsafe { libc::write(self.messages.1.as_raw_fd(), (&raw const message).cast(), 4) };
+    }
+
+    /// Takes the messages written by the child so far.
+    fn read(&self) -> Vec<i32> {
+        let mut messages = Vec::new();
+        let mut message = 0i32;
+        while unsafe { libc::read(self.messages.0.as_raw_fd(), (&raw mut message).cast(), 4) } == 4
+        {
+            messages.push(message);
+        }
+        messages
+    }
+
+    /// Takes the pid of the init, after the child has been spawned.
+    pub(crate) fn init_pid(&self) -> io::Result<u32> {
+        self.read()
+            .into_iter()
+            .find(|&message| message > 0)
+            .map(|pid| pid as u32)
+            .ok_or_else(|| io::Error::other("the init of the pid namespace has not been spawned"))
+    }
+
+    /// Takes the step failed in the child, if spawning failed before exec.
+    pub(crate) fn failed(&self) -> Option<Step> {
+        self.read()
+            .into_iter()
+            .find(|&message| message < 0)
+            .and_then(|step| Step::from_u8(u8::try_from(-step).ok()?))
+    }
+}
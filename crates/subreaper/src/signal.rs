@@ -0,0 +1,181 @@
+//! Signal names and numbers.
+
+use std::str::FromStr;
+use std::{
+    fmt,
+    io,
+};
+
+use futures::prelude::*;
+use futures::stream::{
+    self,
+    BoxStream,
+};
+use tokio::signal::unix::{
+    SignalKind,
+    signal,
+};
+
+/// A signal number.
+///
+/// Can be parsed from its name with or without the `SIG` prefix, e.g., `TERM` or `SIGTERM`, or
+/// from its number, e.g., `15`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Signal(pub(crate) libc::c_int);
+
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+    ("SYS", libc::SIGSYS),
+];
+
+/// Signals relayed to the child.
+//
+// SIGTERM:  stop monitored process
+// SIGINT:   e.g., Ctrl-C at terminal
+// SIGQUIT:  e.g., Ctrl-\ at terminal
+// SIGHUP:   e.g., terminal closed, or reloading configurations
+// SIGUSR1:  user-defined
+// SIGUSR2:  user-defined
+// SIGWINCH: terminal resized
+pub(crate) const FORWARDED: &[libc::c_int] = &[
+    libc::SIGHUP,
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTERM,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+    libc::SIGWINCH,
+];
+
+/// How to forward signals to the child.
+#[derive(Debug, Default, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct Forward {
+    /// Translate a signal before forwarding it to the child, e.g., `TERM=INT,HUP=USR1`.
+    #[arg(long = "signal-map", value_name = "FROM=TO", value_delimiter = ',')]
+    map: Vec<Mapping>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Mapping {
+    from: Signal,
+    to:   Signal,
+}
+
+/// A signal subreaper received, and the one forwarded to the child.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Forwarded {
+    pub(crate) received: Signal,
+    pub(crate) sent:     Signal,
+}
+
+impl Forward {
+    /// Listens to the signals to be forwarded.
+    ///
+    /// Once listened, the default actions of these signals, e.g., terminating subreaper itself,
+    /// are no longer taken.
+    pub(crate) fn listen(signals: &[libc::c_int]) -> io::Result<BoxStream<'static, Signal>> {
+        let signals = signals
+            .iter()
+            .map(|&signum| {
+                let listener = signal(SignalKind::from_raw(signum))?;
+                Ok(stream::unfold(listener, move |mut listener| async move {
+                    listener.recv().await.map(|()| (Signal(signum), listener))
+                })
+                .boxed())
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(stream::select_all(signals).boxed())
+    }
+
+    /// Translates the received signal into the one to be forwarded.
+    pub(crate) fn translate(&self, received: Signal) -> Forwarded {
+        let sent = self.map.iter().rev().find(|m| m.from == received).map_or(received, |m| m.to);
+        Forwarded { received, sent }
+    }
+}
+
+impl FromStr for Mapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((from, to)) = s.split_once('=') else {
+            return Err(format!("expected FROM=TO, but got '{s}'"));
+        };
+        let from = from.trim().parse::<Signal>()?;
+        if !FORWARDED.contains(&from.0) {
+            return Err(format!("{from} is not forwarded"));
+        }
+        Ok(Mapping { from, to: to.trim().parse()? })
+    }
+}
+
+impl Signal {
+    pub(crate) const INT: Signal = Signal(libc::SIGINT);
+    pub(crate) const KILL: Signal = Signal(libc::SIGKILL);
+    pub(crate) const QUIT: Signal = Signal(libc::SIGQUIT);
+    pub(crate) const TERM: Signal = Signal(libc::SIGTERM);
+    pub(crate) const WINCH: Signal = Signal(libc::SIGWINCH);
+
+    /// Returns the name without the `SIG` prefix, if it has.
+    pub(crate) fn name(self) -> Option<&'static str> {
+        SIGNALS.iter().find(|(_, signum)| *signum == self.0).map(|(name, _)| *name)
+    }
+}
+
+impl FromStr for Signal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(signum) = s.parse::<libc::c_int>() {
+            return if (1..=libc::SIGRTMAX()).contains(&signum) {
+                Ok(Signal(signum))
+            } else {
+                Err(format!("invalid signal number: {signum}"))
+            };
+        }
+
+        let name = s.to_ascii_uppercase();
+        let name = name.strip_prefix("SIG").unwrap_or(&name);
+        SIGNALS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, signum)| Signal(*signum))
+            .ok_or_else(|| format!("unknown signal: {s}"))
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "SIG{name}"),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
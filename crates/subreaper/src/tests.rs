@@ -1,17 +1,60 @@
-use std::fs;
+use std::collections::HashMap;
 use std::path::{
     Path,
     PathBuf,
 };
+use std::time::{
+    Duration,
+    Instant,
+};
+use std::{
+    fs,
+    io,
+};
 
 use testing::TempDirExt;
-use tokio::task;
+use tokio::{
+    task,
+    time,
+};
 
+use super::attrs::Attrs;
+use super::exit::{
+    Cause,
+    ExitCodes,
+};
+use super::kill::Kill;
+use super::signal::{
+    Forward,
+    Forwarded,
+    Signal,
+};
+use super::wait::{
+    Wait,
+    wait_for,
+};
 use super::{
+    Flags,
     SpawnError,
-    wait_for,
+    parse_env_file,
 };
 
+#[test]
+fn verify_flags() {
+    <Flags as clap::CommandFactory>::command().debug_assert();
+}
+
+#[test]
+fn default_flags() {
+    let flags = Flags { program: Some("true".into()), ..Flags::default() };
+    assert_eq!(parse::<Flags>(&["--", "true"]).unwrap(), flags);
+}
+
+/// Parses the arguments into the flags alone, as if given on the command line.
+fn parse<T: clap::Parser>(args: &[&str]) -> Result<T, clap::Error> {
+    T::try_parse_from(std::iter::once("subreaper").chain(args.iter().copied()))
+}
+
 fn create_files<P, T>(temp_dir: &testing::TempDir, paths: T) -> Vec<PathBuf>
 where
     P: AsRef<Path>,
@@ -77,3 +120,210 @@ async fn wait_for_files() {
 
     temp_dir.close().unwrap();
 }
+
+#[tokio::test]
+async fn wait_for_files_created_later() {
+    let temp_dir = testing::tempdir();
+    let ok = temp_dir.path().join("not/yet/created/ok");
+
+    let started = Instant::now();
+    let h = task::spawn({
+        let ok = ok.clone();
+        async move { wait_for(&[ok]).await }
+    });
+    time::sleep(Duration::from_millis(50)).await;
+    fs::create_dir_all(ok.parent().unwrap()).unwrap();
+    fs::write(&ok, "").unwrap();
+    h.await.unwrap().expect("should be ok");
+    assert!(started.elapsed() < Duration::from_millis(900), "notified without polling");
+}
+
+#[tokio::test]
+async fn wait_for_probes() {
+    let temp_dir = testing::tempdir();
+    let wait = |args: &[&str]| parse::<Wait>(args).unwrap();
+
+    let tcp = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = tcp.local_addr().unwrap().to_string();
+    wait(&["--wait-tcp", &addr]).ready().await.expect("the port is listening");
+    drop(tcp);
+    match wait(&["--wait-tcp", &addr, "--wait-timeout", "200ms"]).ready().await {
+        Err(SpawnError::WaitTimeout(timeout)) => assert_eq!(timeout, Duration::from_millis(200)),
+        others => panic!("unexpected result: {others:?}"),
+    }
+
+    let sock = temp_dir.path().join("sock");
+    let h = task::spawn({
+        let sock = sock.to_str().unwrap().to_owned();
+        async move { wait(&["--wait-unix", &sock, "--wait-interval", "10ms"]).ready().await }
+    });
+    time::sleep(Duration::from_millis(50)).await;
+    let _unix = tokio::net::UnixListener::bind(&sock).unwrap();
+    h.await.unwrap().expect("the socket is listening");
+
+    let flag = temp_dir.path().join("flag");
+    let test = format!("test -e {}", flag.display());
+    let h = task::spawn(async move { wait(&["--wait-cmd", &test]).ready().await });
+    time::sleep(Duration::from_millis(50)).await;
+    fs::write(&flag, "").unwrap();
+    h.await.unwrap().expect("the command exits with 0");
+}
+
+#[test]
+fn parse_env_files() {
+    let vars = HashMap::from([("HOME".to_owned(), "/home/subreaper".to_owned())]);
+
+    let envs = parse_env_file(b"", vars.clone()).unwrap();
+    assert_eq!(envs, []);
+
+    let source = br#"
+A=1
+B="x y" C='$HOME'
+D="$HOME/bin" E=$UNDEFINED
+"#;
+    let envs = parse_env_file(source, vars.clone()).unwrap();
+    assert_eq!(
+        envs,
+        [
+            ("A".to_owned(), "1".to_owned()),
+            ("B".to_owned(), "x y".to_owned()),
+            ("C".to_owned(), "$HOME".to_owned()),
+            ("D".to_owned(), "/home/subreaper/bin".to_owned()),
+            ("E".to_owned(), "".to_owned()),
+        ]
+    );
+
+    let err = parse_env_file(b"A=1 B", vars.clone()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let err = parse_env_file(b"=1", vars).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn parse_signals() {
+    assert_eq!("TERM".parse(), Ok(Signal(libc::SIGTERM)));
+    assert_eq!("SIGTERM".parse(), Ok(Signal(libc::SIGTERM)));
+    assert_eq!("sigint".parse(), Ok(Signal(libc::SIGINT)));
+    assert_eq!("9".parse(), Ok(Signal(libc::SIGKILL)));
+    assert!("0".parse::<Signal>().is_err());
+    assert!("SIGFOO".parse::<Signal>().is_err());
+
+    assert_eq!(Signal(libc::SIGUSR1).to_string(), "SIGUSR1");
+    assert_eq!(Signal(libc::SIGRTMIN()).to_string(), libc::SIGRTMIN().to_string());
+}
+
+#[test]
+fn parse_kill_ladders() {
+    let kill = |args: &[&str]| {
+        parse::<Kill>(args)
+            .map(|kill| kill.ladder().into_iter().map(|step| format!("{step}")).collect::<Vec<_>>())
+    };
+
+    assert_eq!(kill(&[]).unwrap(), ["SIGTERM:500ms", "SIGKILL"]);
+    assert_eq!(
+        kill(&["--kill-signal", "INT", "--kill-grace", "3s"]).unwrap(),
+        ["SIGINT:3s", "SIGKILL"]
+    );
+    assert_eq!(kill(&["--drain-grace", "1s"]).unwrap(), ["SIGTERM:1s", "SIGKILL"]);
+    assert_eq!(
+        kill(&["--kill-ladder", "INT:2s,TERM:5s,KILL"]).unwrap(),
+        ["SIGINT:2s", "SIGTERM:5s", "SIGKILL"]
+    );
+    assert_eq!(
+        kill(&["--kill-ladder", "INT:2s,TERM:5s"]).unwrap(),
+        ["SIGINT:2s", "SIGTERM:5s", "SIGKILL"]
+    );
+    assert_eq!(kill(&["--kill-ladder", "HUP"]).unwrap(), ["SIGHUP"]);
+
+    assert!(kill(&["--kill-ladder", "INT,KILL"]).is_err());
+    assert!(kill(&["--kill-ladder", "INT:xx"]).is_err());
+    assert!(kill(&["--kill-ladder", ""]).is_err());
+    assert!(kill(&["--kill-ladder", "INT:1s", "--kill-signal", "TERM"]).is_err());
+}
+
+#[test]
+fn translate_signals() {
+    let identity = parse::<Forward>(&[]).unwrap();
+    assert_eq!(
+        identity.translate(Signal::TERM),
+        Forwarded { received: Signal::TERM, sent: Signal::TERM }
+    );
+
+    let map = parse::<Forward>(&["--signal-map", "TERM=INT,HUP=USR1", "--signal-map", "HUP=QUIT"])
+        .unwrap();
+    assert_eq!(map.translate(Signal::TERM).sent, Signal::INT);
+    assert_eq!(map.translate(Signal(libc::SIGHUP)).sent, Signal(libc::SIGQUIT));
+    assert_eq!(map.translate(Signal::INT).sent, Signal::INT);
+
+    assert!(parse::<Forward>(&["--signal-map", "TERM"]).is_err());
+    assert!(parse::<Forward>(&["--signal-map", "TERM=FOO"]).is_err());
+    assert!(
+        parse::<Forward>(&["--signal-map", "KILL=TERM"]).is_err(),
+        "SIGKILL cannot be forwarded"
+    );
+}
+
+#[test]
+fn parse_attrs() {
+    let rlimits =
+        parse::<Attrs>(&["--rlimit", "nofile=4096,core=0", "--rlimit", "stack=8192:unlimited"])
+            .unwrap()
+            .rlimits
+            .into_iter()
+            .map(|rlimit| (rlimit.resource, rlimit.soft, rlimit.hard))
+            .collect::<Vec<_>>();
+    assert_eq!(
+        rlimits,
+        [
+            (libc::RLIMIT_NOFILE as libc::c_int, 4096, 4096),
+            (libc::RLIMIT_CORE as libc::c_int, 0, 0),
+            (libc::RLIMIT_STACK as libc::c_int, 8192, libc::RLIM_INFINITY),
+        ]
+    );
+    assert!(parse::<Attrs>(&["--rlimit", "nofile"]).is_err());
+    assert!(parse::<Attrs>(&["--rlimit", "files=1"]).is_err());
+    assert!(parse::<Attrs>(&["--rlimit", "nofile=2:1"]).is_err());
+
+    let cpus = |list: &str| {
+        parse::<Attrs>(&["--cpu-affinity", list]).map(|attrs| attrs.cpu_affinity.unwrap().0)
+    };
+    assert_eq!(cpus("0-3,6").unwrap(), [0, 1, 2, 3, 6]);
+    assert_eq!(cpus("2").unwrap(), [2]);
+    assert!(cpus("").is_err());
+    assert!(cpus("a-b").is_err());
+
+    assert!(parse::<Attrs>(&["--nice", "-5"]).is_ok());
+    assert!(parse::<Attrs>(&["--nice", "20"]).is_err());
+    assert!(parse::<Attrs>(&["--oom-score-adj", "-1001"]).is_err());
+}
+
+#[test]
+fn parse_exit_codes() {
+    let default = parse::<ExitCodes>(&[]).unwrap();
+    assert!(default.is_success(0));
+    assert!(!default.is_success(3));
+    assert_eq!(default.code(Cause::Timeout), 124);
+    assert_eq!(default.code(Cause::IdleTimeout), 123);
+    assert_eq!(default.code(Cause::Signaled(Signal::TERM)), 143);
+
+    let mapped = parse::<ExitCodes>(&[
+        "--success-exit-codes",
+        "0,3,75",
+        "--map-exit",
+        "75=0,timeout=0",
+        "--map-exit",
+        "signal:SEGV=139,idle-timeout=2",
+    ])
+    .unwrap();
+    assert!(mapped.is_success(3));
+    assert_eq!(mapped.code(Cause::Exited(75)), 0);
+    assert_eq!(mapped.code(Cause::Exited(74)), 74);
+    assert_eq!(mapped.code(Cause::Timeout), 0);
+    assert_eq!(mapped.code(Cause::IdleTimeout), 2);
+    assert_eq!(mapped.code(Cause::Signaled(Signal(libc::SIGSEGV))), 139);
+
+    assert!(parse::<ExitCodes>(&["--map-exit", "75"]).is_err());
+    assert!(parse::<ExitCodes>(&["--map-exit", "256=0"]).is_err());
+    assert!(parse::<ExitCodes>(&["--map-exit", "signal:NOSIG=1"]).is_err());
+    assert!(parse::<ExitCodes>(&["--success-exit-codes", "-1"]).is_err());
+}
@@ -0,0 +1,228 @@
+//! Waiting for the dependencies of the child to become ready before spawning it.
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{
+    AsRawFd,
+    FromRawFd,
+    OwnedFd,
+};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::process::Stdio;
+use std::time::Duration;
+
+use futures::future;
+use futures::prelude::*;
+use tokio::io::unix::AsyncFd;
+use tokio::net::{
+    TcpStream,
+    UnixStream,
+};
+use tokio::time;
+use tracing::trace;
+
+use crate::{
+    SpawnError,
+    Subreaper,
+};
+
+/// Conditions to be met before spawning the child.
+#[derive(Debug, Clone, PartialEq, Eq, clap::Parser)]
+pub(crate) struct Wait {
+    /// Wait for the file to exist before spawning the child process.
+    ///
+    /// Fails if the error file exists instead, i.e., the file with the extension `err`.
+    #[arg(long = "wait", value_name = "PATH")]
+    wait_files: Vec<PathBuf>,
+
+    /// Wait for the TCP port to accept connections before spawning the child process.
+    #[arg(long = "wait-tcp", value_name = "HOST:PORT")]
+    wait_tcp: Vec<String>,
+
+    /// Wait for the unix socket to accept connections before spawning the child process.
+    #[arg(long = "wait-unix", value_name = "PATH")]
+    wait_unix: Vec<PathBuf>,
+
+    /// Wait for the shell command to exit with 0 before spawning the child process.
+    #[arg(long = "wait-cmd", value_name = "COMMAND")]
+    wait_cmd: Vec<String>,
+
+    /// Give up waiting after the duration.
+    ///
+    /// Note that `--kill-after` does not elapse until the child is spawned.
+    #[arg(
+        long = "wait-timeout",
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration,
+    )]
+    wait_timeout: Option<Duration>,
+
+    /// How often to retry the TCP, unix socket and command probes.
+    #[arg(
+        long = "wait-interval",
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration,
+        default_value = "100ms",
+    )]
+    wait_interval: Duration,
+}
+
+impl Default for Wait {
+    fn default() -> Self {
+        Wait {
+            wait_files:    Vec::new(),
+            wait_tcp:      Vec::new(),
+            wait_unix:     Vec::new(),
+            wait_cmd:      Vec::new(),
+            wait_timeout:  None,
+            wait_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Rechecks the files at this interval even without any events, e.g., on network filesystems.
+const POLL_INTERVAL: Duration = Duration::from_millis(1000);
+
+impl Wait {
+    /// Waits until all the conditions are met.
+    pub(crate) async fn ready(&self) -> Result<(), SpawnError> {
+        let tcp = self.wait_tcp.iter().map(|addr| {
+            retry(self.wait_interval, move || TcpStream::connect(addr.as_str()).map_ok(drop))
+                .boxed()
+        });
+        let unix = self.wait_unix.iter().map(|path| {
+            retry(self.wait_interval, move || UnixStream::connect(path).map_ok(drop)).boxed()
+        });
+        let cmd =
+            self.wait_cmd.iter().map(|cmd| retry(self.wait_interval, move || sh(cmd)).boxed());
+        let probes = future::join_all(tcp.chain(unix).chain(cmd));
+
+        let ready = future::try_join(wait_for(&self.wait_files), probes.map(Ok));
+        match self.wait_timeout {
+            None => ready.await.map(drop),
+            Some(timeout) => match time::timeout(timeout, ready).await {
+                Ok(ready) => ready.map(drop),
+                Err(_) => Err(SpawnError::WaitTimeout(timeout)),
+            },
+        }
+    }
+}
+
+/// Retries the probe until it succeeds.
+async fn retry<F, Fut>(interval: Duration, probe: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = io::Result<()>>,
+{
+    while let Err(err) = probe().await {
+        trace!("not ready yet: {err}");
+        time::sleep(interval).await;
+    }
+}
+
+/// Runs the command by `sh -c`, and succeeds if it exits with 0.
+async fn sh(cmd: &str) -> io::Result<()> {
+    let mut sh = tokio::process::Command::new("sh");
+    sh.args(["-c", cmd]).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+    // The subreaper reaps all children, so the exit status is delivered through it.
+    let (_child, _, exit) = Subreaper::spawn(&mut sh, None)?;
+    let reaped = exit.await?;
+    if reaped.exit_status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("`{cmd}` {}", reaped.exit_status)))
+    }
+}
+
+/// Waits for the files to exist, or fails if any of the error files exist.
+pub(crate) async fn wait_for(paths: &[PathBuf]) -> Result<(), SpawnError> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+
+    // Falls back to polling if inotify is not available.
+    let watcher = Watcher::new().inspect_err(|err| trace!("failed to watch files: {err}")).ok();
+    loop {
+        // Watches before checking, not to miss files created in the meantime.
+        if let Some(watcher) = &watcher {
+            for path in paths {
+                watcher.watch(path);
+            }
+        }
+
+        let mut ready = true;
+        for ok_file in paths {
+            trace!(wait_for = %ok_file.display());
+            let err_file = ok_file.with_extension("err");
+            if err_file.try_exists().map_err(SpawnError::Io)? {
+                return Err(SpawnError::FoundErrFile(err_file));
+            }
+            ready &= ok_file.try_exists().map_err(SpawnError::Io)?;
+        }
+        if ready {
+            return Ok(());
+        }
+
+        match &watcher {
+            Some(watcher) => {
+                let _ = time::timeout(POLL_INTERVAL, watcher.changed()).await;
+            }
+            None => time::sleep(POLL_INTERVAL).await,
+        }
+    }
+}
+
+/// Notifies changes in the directories containing the files waited for.
+struct Watcher {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl Watcher {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(Watcher { fd: AsyncFd::new(fd)? })
+    }
+
+    /// Watches the nearest existing ancestor of the path.
+    ///
+    /// Watching the same directory again is a no-op, so this is called whenever something changes,
+    /// to watch the directories created in the meantime.
+    fn watch(&self, path: &Path) {
+        let mask = libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_ATTRIB | libc::IN_DELETE_SELF;
+        for dir in path.ancestors().skip(1) {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            let Ok(cdir) = CString::new(dir.as_os_str().as_bytes()) else {
+                return;
+            };
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), cdir.as_ptr(), mask) };
+            if wd >= 0 {
+                return;
+            }
+        }
+    }
+
+    /// Waits for any events, and discards them.
+    async fn changed(&self) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let read = guard.try_io(|fd| {
+                let n = unsafe {
+                    libc::read(fd.get_ref().as_raw_fd(), buf.as_mut_ptr().cast(), buf.len())
+                };
+                if n < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+            });
+            if let Ok(read) = read {
+                return read;
+            }
+        }
+    }
+}
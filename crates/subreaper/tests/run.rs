@@ -7,11 +7,15 @@ use std::io::{
 };
 use std::path::PathBuf;
 use std::process::{
+    Child,
     Command,
     Stdio,
 };
 use std::thread;
-use std::time::Duration;
+use std::time::{
+    Duration,
+    Instant,
+};
 
 use faccess::faccess;
 
@@ -98,6 +102,61 @@ fn subreaper_orphan_behave_as_expected() {
     }
 }
 
+#[test]
+fn subreaper_writes_report() {
+    let temp_dir = testing::tempdir();
+    let report = temp_dir.path().join("report/exit.json");
+
+    let script = format!("{} 3; exit 7", orphan().display());
+    let r = Command::new(subreaper())
+        .arg("--report")
+        .arg(&report)
+        .args(["--", "sh", "-c", &script])
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert_eq!(r.code(), Some(7));
+
+    let report: serde_json::Value =
+        serde_json::from_reader(std::fs::File::open(&report).unwrap()).unwrap();
+    assert_eq!(report["command"], serde_json::json!(["sh", "-c", script]));
+    assert_eq!(report["pid"], report["pgid"]);
+    assert_eq!(report["exit_code"], 7);
+    assert_eq!(report["child"]["code"], 7);
+    assert_eq!(report["child"]["signal"], serde_json::Value::Null);
+    assert_eq!(report["timedout"], false);
+    assert_eq!(report["signaled"], serde_json::Value::Null);
+    // The parent of orphans, and maybe orphans themselves.
+    assert!(!report["orphans"].as_array().unwrap().is_empty());
+    assert!(report["started_at"].as_str().unwrap() <= report["ended_at"].as_str().unwrap());
+    assert!(report["rusage"]["child"]["max_rss_kb"].as_u64().unwrap() > 0);
+    assert_eq!(
+        report["rusage"]["orphans"].as_array().unwrap().len(),
+        report["orphans"].as_array().unwrap().len()
+    );
+    assert!(
+        report["rusage"]["total"]["minor_faults"].as_u64().unwrap()
+            >= report["rusage"]["child"]["minor_faults"].as_u64().unwrap()
+    );
+
+    let report = temp_dir.path().join("timeout.json");
+    let r = Command::new(subreaper())
+        .arg("--report")
+        .arg(&report)
+        .args(["--kill-after", "10ms", "--", "sleep", "10"])
+        .status()
+        .unwrap();
+    assert_eq!(r.code(), Some(124));
+
+    let report: serde_json::Value =
+        serde_json::from_reader(std::fs::File::open(&report).unwrap()).unwrap();
+    assert_eq!(report["exit_code"], 124);
+    assert_eq!(report["child"]["code"], serde_json::Value::Null);
+    assert_eq!(report["child"]["signal"], "SIGTERM");
+    assert_eq!(report["timedout"], true);
+    assert_eq!(report["cause"], "timeout");
+}
+
 fn all_eq<I>(it: I) -> bool
 where
     I: IntoIterator,
@@ -189,3 +248,865 @@ fn subreaper_exits_with_124_when_timedout() {
     assert!(sleep.status.success());
     assert_eq!(sleep.status.code(), Some(0));
 }
+
+#[test]
+fn subreaper_exits_with_123_when_idle() {
+    let idle = |script: &str| {
+        Command::new(subreaper())
+            .args(["--idle-timeout", "300ms", "--kill-after", "10s", "--", "sh", "-c", script])
+            .stdout(Stdio::null())
+            .status()
+            .unwrap()
+    };
+
+    let status = idle("echo started; sleep 10");
+    assert_eq!(status.code(), Some(123));
+
+    // Keeps writing for longer than the idle timeout.
+    let status = idle("for i in 1 2 3 4 5 6 7 8; do echo $i; sleep 0.1; done");
+    assert_eq!(status.code(), Some(0));
+}
+
+#[test]
+fn subreaper_warns_before_the_deadline() {
+    let script = "trap 'echo warned; exit 0' USR1; sleep 5 & wait";
+    let (stdout, _) = output(
+        &["--output=passthrough", "--warn-after=200ms", "--warn-signal=USR1", "--kill-after=10s"],
+        script,
+    );
+    assert_eq!(stdout, b"warned\n");
+}
+
+fn is_alive(pid: libc::pid_t) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+#[test]
+fn subreaper_kills_daemonized_descendants() {
+    let temp_dir = testing::tempdir();
+    let pid_file = temp_dir.path().join("pid");
+
+    // The grandchild leaves the process group of the child, and ignores SIGTERM.
+    let script = format!(
+        r#"setsid sh -c 'trap "" TERM; echo $$ > {0}; exec sleep 30' &
+        while [ ! -s {0} ]; do sleep 0.01; done"#,
+        pid_file.display()
+    );
+    let r = Command::new(subreaper())
+        .args(["--kill-grace", "100ms", "--", "sh", "-c", &script])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .env("SUBREAPER_LOG", "info")
+        .output()
+        .unwrap();
+    assert!(r.status.success());
+
+    let pid = std::fs::read_to_string(&pid_file).unwrap().trim().parse::<libc::pid_t>().unwrap();
+    assert!(!is_alive(pid), "{pid} outlives subreaper");
+
+    let stderr = String::from_utf8_lossy(&r.stderr);
+    assert!(stderr.contains("killed descendants left behind"), "{stderr}");
+    assert!(stderr.contains(&pid.to_string()), "{stderr}");
+}
+
+#[test]
+fn subreaper_controls_environment() {
+    let temp_dir = testing::tempdir();
+    let env_file = temp_dir.path().join("env");
+    std::fs::write(&env_file, "FROM_FILE=\"$SUBREAPER_TEST_VAR/file\"\nOVERRIDDEN=file\n").unwrap();
+
+    let check = |args: &[&str], script: &str| {
+        Command::new(subreaper())
+            .args(args)
+            .args(["--", "sh", "-c", script])
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .env("SUBREAPER_TEST_VAR", "parent")
+            .status()
+            .unwrap()
+            .success()
+    };
+
+    assert!(check(&[], r#"test "$SUBREAPER_TEST_VAR" = parent"#));
+    assert!(check(&["--clear-env"], r#"test -z "$SUBREAPER_TEST_VAR""#));
+    assert!(check(
+        &["--clear-env", "--env", "SUBREAPER_TEST_VAR", "--env", "FOO=bar=baz"],
+        r#"test "$SUBREAPER_TEST_VAR" = parent && test "$FOO" = bar=baz"#
+    ));
+    assert!(check(
+        &["--clear-env", "--env-file", env_file.to_str().unwrap(), "--env", "OVERRIDDEN=flag"],
+        r#"test "$FROM_FILE" = parent/file && test "$OVERRIDDEN" = flag && test -z "$SUBREAPER_TEST_VAR""#
+    ));
+    assert!(!check(&["--env-file", "/xxx/not/found"], "true"));
+}
+
+#[test]
+fn subreaper_delivers_child_exit_among_many_orphans() {
+    // If the exit of the child were lost, subreaper would time out.
+    let orphans =
+        timeout(OsStr::new("60s"), [orphan().as_os_str(), OsStr::new("300")]).output().unwrap();
+    assert_eq!(orphans.status.code(), Some(0));
+
+    let script = format!("{0} 100 & {0} 100 & {0} 100; wait; exit 3", orphan().display());
+    let orphans = timeout("60s", ["sh", "-c", &script]).output().unwrap();
+    assert_eq!(orphans.status.code(), Some(3));
+}
+
+#[test]
+fn subreaper_kills_with_the_given_signal_on_timeout() {
+    let temp_dir = testing::tempdir();
+    let trapped = temp_dir.path().join("trapped");
+
+    let run = |code: i32| {
+        let script = format!(
+            r#"trap "touch {}; exit {code}" INT; while :; do sleep 0.01; done"#,
+            trapped.display()
+        );
+        Command::new(subreaper())
+            .args(["--kill-after", "100ms", "--kill-signal", "INT", "--", "sh", "-c", &script])
+            .status()
+            .unwrap()
+    };
+    assert_eq!(run(3).code(), Some(124));
+    assert!(trapped.exists());
+    assert_eq!(run(0).code(), Some(0), "the child stopped gracefully on timeout");
+}
+
+#[test]
+fn subreaper_escalates_along_the_ladder() {
+    let started = Instant::now();
+    let r = Command::new(subreaper())
+        .args(["--kill-after", "100ms", "--kill-ladder", "INT:100ms,TERM:100ms"])
+        .args(["--", "sh", "-c", r#"trap "" INT TERM; while :; do sleep 0.01; done"#])
+        .status()
+        .unwrap();
+    assert_eq!(r.code(), Some(124));
+    // Killed by SIGKILL appended to the ladder, much earlier than 10s.
+    assert!(started.elapsed() < Duration::from_secs(10));
+
+    let r = Command::new(subreaper())
+        .args(["--kill-ladder", "INT:1s,TERM", "--kill-grace", "1s", "--", "true"])
+        .status()
+        .unwrap();
+    assert_eq!(r.code(), Some(2), "--kill-ladder conflicts with --kill-grace");
+}
+
+/// Spawns subreaper running the script, and waits until the script creates the `ready` file.
+fn spawn_ready(temp_dir: &testing::TempDir, args: &[&str], script: &str) -> Child {
+    let ready = temp_dir.path().join("ready");
+    let script = format!("{script}\ntouch {}\nwhile :; do sleep 0.01; done", ready.display());
+    let child =
+        Command::new(subreaper()).args(args).args(["--", "sh", "-c", &script]).spawn().unwrap();
+    while !ready.exists() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    child
+}
+
+#[test]
+fn subreaper_forwards_signals() {
+    let temp_dir = testing::tempdir();
+    let usr1 = temp_dir.path().join("usr1");
+
+    let script = format!(r#"trap "touch {}" USR1; trap "exit 3" HUP"#, usr1.display());
+    let mut child = spawn_ready(&temp_dir, &[], &script);
+    unsafe { libc::kill(child.id() as i32, libc::SIGUSR1) };
+    while !usr1.exists() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    // SIGHUP does not stop subreaper itself.
+    unsafe { libc::kill(child.id() as i32, libc::SIGHUP) };
+    assert_eq!(child.wait().unwrap().code(), Some(3));
+}
+
+#[test]
+fn subreaper_translates_signals() {
+    let temp_dir = testing::tempdir();
+
+    let mut child = spawn_ready(
+        &temp_dir,
+        &["--signal-map", "TERM=USR2"],
+        r#"trap "exit 0" TERM; trap "exit 5" USR2"#,
+    );
+    unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+    assert_eq!(child.wait().unwrap().code(), Some(143), "exit code reflects the received signal");
+}
+
+/// Runs the script under subreaper with the args, and returns stdout and stderr.
+fn output(args: &[&str], script: &str) -> (Vec<u8>, Vec<u8>) {
+    let out = Command::new(subreaper())
+        .args(args)
+        .args(["--", "sh", "-c", script])
+        .env("SUBREAPER_LOG", "off")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    (out.stdout, out.stderr)
+}
+
+#[test]
+fn subreaper_relays_output() {
+    let script = r#"printf 'a\000\377\nb'; printf 'c\nd' >&2"#;
+
+    let (stdout, stderr) = output(&["--output", "passthrough"], script);
+    assert_eq!(stdout, b"a\0\xff\nb", "binary output and partial lines survive");
+    assert_eq!(stderr, b"c\nd");
+
+    let (stdout, stderr) = output(&["--output", "prefix"], script);
+    let stdout = String::from_utf8_lossy(&stdout);
+    let stderr = String::from_utf8_lossy(&stderr);
+    assert_eq!(stdout.lines().count(), 2);
+    assert!(stdout.lines().all(|line| line.contains(" stdout: ")));
+    assert!(stdout.ends_with(" stdout: b"), "the partial line is left partial: {stdout}");
+    assert!(stderr.lines().all(|line| line.contains(" stderr: ")));
+    assert!(stderr.ends_with(" stderr: d"), "{stderr}");
+
+    let (stdout, stderr) = output(&["--output", "json"], script);
+    assert!(stderr.is_empty());
+    let lines = stdout
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4);
+    let of = |stream: &str| {
+        lines.iter().filter(|line| line["stream"] == stream).cloned().collect::<Vec<_>>()
+    };
+    assert_eq!(of("stdout")[0]["bytes"], serde_json::json!([b'a', 0, 0xff]));
+    assert_eq!(of("stdout")[0].get("partial"), None);
+    assert_eq!(of("stdout")[1]["line"], "b");
+    assert_eq!(of("stdout")[1]["partial"], true);
+    assert_eq!(of("stderr")[0]["line"], "c");
+    assert_eq!(of("stderr")[1]["line"], "d");
+    assert_eq!(of("stderr")[1]["partial"], true);
+
+    let out = Command::new(subreaper())
+        .args(["--output", "log", "--", "sh", "-c", script])
+        .env("SUBREAPER_LOG", "info")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let logged = |line: &str| stderr.lines().any(|logged| logged == line);
+    assert!(logged(r#"b stream="stdout""#), "{stderr}");
+    assert!(logged(r#"c stream="stderr""#), "{stderr}");
+}
+
+#[test]
+fn subreaper_rotates_output_files() {
+    let temp_dir = testing::tempdir();
+    let stdout_file = temp_dir.path().join("stdout");
+    let stderr_file = temp_dir.path().join("stderr");
+
+    let script = "for i in 1 2 3 4 5 6 7 8 9; do echo 123456789; sleep 0.01; done; printf err >&2";
+    output(
+        &[
+            "--stdout-file",
+            stdout_file.to_str().unwrap(),
+            "--stderr-file",
+            stderr_file.to_str().unwrap(),
+            "--output-max-size",
+            "25",
+            "--output-max-files",
+            "3",
+        ],
+        script,
+    );
+
+    let read = |suffix: &str| {
+        let mut path = stdout_file.clone().into_os_string();
+        path.push(suffix);
+        std::fs::read_to_string(path).ok()
+    };
+    assert_eq!(read("").as_deref(), Some("123456789\n"));
+    assert_eq!(read(".1").as_deref(), Some("123456789\n123456789\n"));
+    assert_eq!(read(".2").as_deref(), Some("123456789\n123456789\n"));
+    assert_eq!(read(".3").as_deref(), Some("123456789\n123456789\n"));
+    assert_eq!(read(".4"), None);
+    assert_eq!(std::fs::read_to_string(&stderr_file).unwrap(), "err");
+}
+
+#[test]
+fn subreaper_restarts_the_child() {
+    let temp_dir = testing::tempdir();
+    let count = temp_dir.path().join("count");
+    let report = temp_dir.path().join("report.json");
+    let on_exit = temp_dir.path().join("exited");
+
+    // Counts the runs, and exits with the given code until the limit.
+    let run = |args: &[&str], limit: u32, code: i32| {
+        std::fs::write(&count, "").unwrap();
+        let script = format!(
+            r#"echo >> {count}; test $(wc -l < {count}) -ge {limit} && exit 0; exit {code}"#,
+            count = count.display(),
+        );
+        let status = Command::new(subreaper())
+            .args(["--restart-delay", "10ms", "--report", report.to_str().unwrap()])
+            .args(["--on-exit", on_exit.to_str().unwrap()])
+            .args(args)
+            .args(["--", "sh", "-c", &script])
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        let runs = std::fs::read_to_string(&count).unwrap().lines().count();
+        let report: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&report).unwrap()).unwrap();
+        (status.code(), runs, report["restarts"].as_u64().unwrap())
+    };
+
+    assert_eq!(run(&[], 9, 3), (Some(3), 1, 0), "no restarts by default");
+    assert_eq!(run(&["--restart", "on-failure"], 3, 3), (Some(0), 3, 2));
+    assert!(on_exit.exists());
+    assert_eq!(run(&["--restart", "on-failure", "--max-restarts", "2"], 9, 3), (Some(3), 3, 2));
+    let mut err = on_exit.clone().into_os_string();
+    err.push(".err");
+    assert!(PathBuf::from(err).exists(), "the marker reflects the last run");
+    assert_eq!(run(&["--restart", "always", "--max-restarts", "1"], 1, 3), (Some(0), 2, 1));
+    assert_eq!(
+        run(&["--restart", "on-failure", "--max-restarts", "2", "--restart-window", "1ms"], 5, 3),
+        (Some(0), 5, 4),
+        "restarts are counted within the window"
+    );
+}
+
+#[test]
+fn subreaper_stops_restarting_when_signaled() {
+    let temp_dir = testing::tempdir();
+    let ran = temp_dir.path().join("ran");
+
+    let mut child = Command::new(subreaper())
+        .args(["--restart", "always", "--restart-delay", "1h"])
+        .args(["--", "sh", "-c", &format!("touch {}; exit 3", ran.display())])
+        .spawn()
+        .unwrap();
+    while !ran.exists() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(100));
+    unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+    assert_eq!(child.wait().unwrap().code(), Some(143));
+}
+
+#[test]
+fn subreaper_runs_procfile() {
+    let temp_dir = testing::tempdir();
+    let procfile = temp_dir.path().join("Procfile");
+
+    let run = |args: &[&str], entries: &str| {
+        std::fs::write(&procfile, entries).unwrap();
+        let now = Instant::now();
+        let out = Command::new(subreaper())
+            .args(["--procfile", procfile.to_str().unwrap(), "--output", "prefix"])
+            .args(args)
+            .stderr(Stdio::inherit())
+            .output()
+            .unwrap();
+        (out.status.code(), String::from_utf8(out.stdout).unwrap(), now.elapsed())
+    };
+
+    let (code, stdout, elapsed) =
+        run(&[], "sh -c 'echo web; sleep 0.2'\nsh -c 'echo worker; exec sleep 60'\n");
+    assert_eq!(code, Some(0), "entries stopped by subreaper are not failures");
+    assert!(elapsed < Duration::from_secs(30));
+    assert!(stdout.contains(" sh stdout: web\n"), "{stdout}");
+    assert!(stdout.contains(" sh.2 stdout: worker\n"), "{stdout}");
+
+    let (code, _, elapsed) = run(&[], "sleep 60\nsh -c 'sleep 0.1; exit 3'\n");
+    assert_eq!(code, Some(3));
+    assert!(elapsed < Duration::from_secs(30));
+
+    let (code, _, elapsed) = run(
+        &["--procfile-policy", "wait-for-all"],
+        "true\nsh -c 'sleep 0.5; exit 4'\nsh -c 'sleep 0.1; exit 5'\n",
+    );
+    assert_eq!(code, Some(5), "the first failure wins");
+    assert!(elapsed >= Duration::from_millis(500), "waits for all entries");
+
+    let (code, stdout, _) = run(
+        &["--procfile-policy", "wait-for-all"],
+        "# named entries\nweb: GREETING=hello sh -c 'echo $GREETING'\nweb: echo again\n",
+    );
+    assert_eq!(code, Some(0));
+    assert!(stdout.contains(" web stdout: hello\n"), "{stdout}");
+    assert!(stdout.contains(" web.2 stdout: again\n"), "{stdout}");
+
+    // The daemon of a running entry is not drained when another entry exits.
+    let done = temp_dir.path().join("done");
+    let daemon = format!("setsid sh -c 'sleep 0.6; touch {}' >/dev/null 2>&1 &", done.display());
+    let entries = format!("daemon: sh -c \"({daemon}); exec sleep 1\"\nother: sleep 0.2\n");
+    let (code, _, _) = run(&["--procfile-policy", "wait-for-all"], &entries);
+    assert_eq!(code, Some(0));
+    assert!(done.exists(), "the daemon outlives the other entry");
+
+    for entries in ["clock:\nweb: echo hi\n", "web: echo hi\n\nA=1\n"] {
+        std::fs::write(&procfile, entries).unwrap();
+        let out = Command::new(subreaper())
+            .args(["--procfile", procfile.to_str().unwrap()])
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(1), "rejected before spawning");
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        let line = if entries.starts_with("clock") { 1 } else { 3 };
+        assert!(stderr.contains(&format!("no command to run at line {line}")), "{stderr}");
+        assert!(out.stdout.is_empty());
+    }
+}
+
+#[test]
+fn subreaper_gives_up_waiting() {
+    let temp_dir = testing::tempdir();
+    let ok = temp_dir.path().join("ok");
+
+    let now = Instant::now();
+    let status = Command::new(subreaper())
+        .args(["--wait", ok.to_str().unwrap(), "--wait-timeout", "100ms", "--", "true"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(now.elapsed() < Duration::from_secs(5));
+}
+
+/// Finds a writable cgroup v2 directory to create transient cgroups under.
+fn delegated_cgroup() -> Option<PathBuf> {
+    let candidates = std::env::var_os("SUBREAPER_TEST_CGROUP")
+        .map(PathBuf::from)
+        .into_iter()
+        .chain(["/sys/fs/cgroup/unified", "/sys/fs/cgroup"].map(PathBuf::from));
+    candidates.into_iter().find(|dir| {
+        let probe = dir.join(format!("subreaper-test.{}", std::process::id()));
+        dir.join("cgroup.controllers").exists()
+            && std::fs::create_dir(&probe).is_ok()
+            && std::fs::remove_dir(&probe).is_ok()
+    })
+}
+
+#[test]
+fn subreaper_contains_the_child_in_cgroup() {
+    let Some(parent) = delegated_cgroup() else {
+        eprintln!("skipped: no writable cgroup v2 directory");
+        return;
+    };
+    let controllers = std::fs::read_to_string(parent.join("cgroup.controllers")).unwrap();
+    let subtree_control = || std::fs::read_to_string(parent.join("cgroup.subtree_control"));
+    let enabled = subtree_control().unwrap();
+    let temp_dir = testing::tempdir();
+    let report = temp_dir.path().join("report.json");
+
+    let out = Command::new(subreaper())
+        .args(["--cgroup", parent.to_str().unwrap(), "--output", "passthrough"])
+        .args(["--report", report.to_str().unwrap()])
+        .args(["--", "sh", "-c", "grep ^0:: /proc/self/cgroup"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let cgroup = String::from_utf8(out.stdout).unwrap();
+    let name = cgroup.trim().rsplit('/').next().unwrap();
+    assert!(name.starts_with("subreaper."), "{cgroup}");
+    assert!(!parent.join(name).exists(), "the transient cgroup is removed");
+    let read_report =
+        || serde_json::from_slice::<serde_json::Value>(&std::fs::read(&report).unwrap()).unwrap();
+    assert_eq!(read_report()["oom_kills"], 0);
+
+    if controllers.split_whitespace().any(|c| c == "pids") {
+        let status = Command::new(subreaper())
+            .args(["--cgroup", parent.to_str().unwrap(), "--pids-max", "1"])
+            .args(["--", "sh", "-c", "sleep 0 && sleep 0"])
+            .status()
+            .unwrap();
+        assert!(!status.success(), "fork should fail");
+    }
+
+    if controllers.split_whitespace().any(|c| c == "memory") {
+        let status = Command::new(subreaper())
+            .args(["--cgroup", parent.to_str().unwrap(), "--memory-max", "16M"])
+            .args(["--report", report.to_str().unwrap()])
+            .args(["--", "sh", "-c", "x=$(head -c 100000000 /dev/zero | tr '\\0' x)"])
+            .status()
+            .unwrap();
+        assert!(!status.success());
+        assert!(read_report()["oom_kills"].as_u64().unwrap() > 0);
+    }
+    assert_eq!(subtree_control().unwrap(), enabled, "the parent is restored");
+
+    // Controllers cannot be enabled under a cgroup with processes.
+    let busy = parent.join(format!("subreaper-test-busy.{}", std::process::id()));
+    std::fs::create_dir(&busy).unwrap();
+    let busy_controllers = std::fs::read_to_string(busy.join("cgroup.controllers")).unwrap();
+    let mut sleep = Command::new("sleep").arg("10").spawn().unwrap();
+    if busy_controllers.split_whitespace().any(|c| c == "pids")
+        && std::fs::write(busy.join("cgroup.procs"), sleep.id().to_string()).is_ok()
+    {
+        let out = Command::new(subreaper())
+            .args(["--cgroup", busy.to_str().unwrap(), "--pids-max", "10", "--", "true"])
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(1));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("has processes"), "{stderr}");
+    }
+    sleep.kill().unwrap();
+    sleep.wait().unwrap();
+    std::fs::remove_dir(&busy).unwrap();
+}
+
+#[test]
+fn subreaper_fails_without_cgroup() {
+    let temp_dir = testing::tempdir();
+    let status = Command::new(subreaper())
+        .args(["--cgroup", temp_dir.path().to_str().unwrap(), "--", "true"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1), "not a cgroup v2 directory");
+}
+
+const UNSHARE: [&str; 5] = ["--pid", "--fork", "--user", "--map-root-user", "--mount-proc"];
+
+/// Whether a pid namespace can be created, which is not permitted in some containers.
+fn can_unshare_pid() -> bool {
+    let permitted = Command::new("unshare")
+        .args(UNSHARE)
+        .arg("true")
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !permitted {
+        eprintln!("skipped: cannot create a pid namespace");
+    }
+    permitted
+}
+
+/// Runs subreaper as pid 1 of a new pid namespace, or returns None if not permitted.
+fn unshare_pid(args: &[&str], script: &str) -> Option<std::process::ExitStatus> {
+    if !can_unshare_pid() {
+        return None;
+    }
+
+    let status = Command::new("unshare")
+        .args(UNSHARE)
+        .arg(subreaper())
+        .args(args)
+        .args(["--", "sh", "-c", script])
+        .status()
+        .unwrap();
+    Some(status)
+}
+
+#[test]
+fn subreaper_runs_as_init() {
+    let temp_dir = testing::tempdir();
+    let done = temp_dir.path().join("done");
+
+    // The kernel drops signals sent to pid 1 without handlers.
+    let script = r#"trap "exit 7" ALRM; kill -ALRM 1; sleep 10 & wait"#;
+    let Some(status) = unshare_pid(&["--init", "--kill-after", "5s"], script) else {
+        return;
+    };
+    assert_eq!(status.code(), Some(7), "SIGALRM is forwarded");
+
+    // The orphan ignoring SIGTERM outlives the child, and subreaper waits for it.
+    // SIGTERM is ignored before forking, not to be terminated before setting the trap.
+    let script = format!(r#"trap "" TERM; sh -c 'sleep 0.3; touch {}' & exit 0"#, done.display());
+    let status = unshare_pid(&["--init"], &script).unwrap();
+    assert!(status.success());
+    assert!(done.exists(), "exits after all descendants are gone");
+
+    // The orphan outliving the child is neither signaled nor killed after --kill-grace.
+    let _ = std::fs::remove_file(&done);
+    let script = format!("sh -c 'sleep 1; touch {}' & exit 0", done.display());
+    let started = Instant::now();
+    let status = unshare_pid(&["--init", "--kill-grace", "100ms"], &script).unwrap();
+    assert!(status.success());
+    assert!(done.exists(), "waits for the orphan to exit on its own");
+    assert!(started.elapsed() >= Duration::from_secs(1));
+
+    // Stops waiting for the orphan when the deadline arrives.
+    let _ = std::fs::remove_file(&done);
+    let script = format!("sh -c 'sleep 3; touch {}' & exit 0", done.display());
+    let started = Instant::now();
+    let status = unshare_pid(&["--init", "--kill-after", "300ms"], &script).unwrap();
+    assert!(status.success(), "the child has exited successfully");
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert!(!done.exists());
+}
+
+#[test]
+fn subreaper_runs_the_child_in_pid_namespace() {
+    if !can_unshare_pid() {
+        return;
+    }
+    let temp_dir = testing::tempdir();
+    let done = temp_dir.path().join("done");
+
+    // The orphan ignoring SIGTERM is killed by the kernel when the child exits.
+    let script = format!(
+        r#"echo $$; ls /proc | grep -c '^[0-9]'; trap "" TERM; sh -c 'sleep 0.3; touch {}' & exit 0"#,
+        done.display()
+    );
+    let (stdout, _) =
+        output(&["--output=passthrough", "--unshare-pid", "--unshare-mount"], &script);
+    let stdout = String::from_utf8(stdout).unwrap();
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "1", "the child is pid 1");
+    assert!(lines[1].parse::<u32>().unwrap() <= 3, "only the namespace is in /proc: {stdout}");
+    thread::sleep(Duration::from_millis(600));
+    assert!(!done.exists(), "no descendant outlives the child");
+
+    // As pid 1, the child ignores SIGTERM without a handler, but not SIGKILL after the grace.
+    let started = Instant::now();
+    let status = Command::new(subreaper())
+        .args(["--unshare-pid", "--kill-after", "200ms", "--", "sleep", "10"])
+        .env("SUBREAPER_LOG", "off")
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(124));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn subreaper_applies_process_attributes() {
+    let script = r#"
+        echo "$(ulimit -n) $(ulimit -c)"
+        cut -d' ' -f19 /proc/self/stat
+        cat /proc/self/oom_score_adj
+        grep -E '^(NoNewPrivs|Cpus_allowed_list):' /proc/self/status
+    "#;
+    let (stdout, _) = output(
+        &[
+            "--output=passthrough",
+            "--rlimit=nofile=64,core=0",
+            "--nice=5",
+            "--oom-score-adj=100",
+            "--cpu-affinity=0",
+            "--no-new-privs",
+        ],
+        script,
+    );
+    let stdout = String::from_utf8(stdout).unwrap();
+    let lines = stdout.lines().map(|line| line.split_whitespace().collect::<Vec<_>>());
+    assert_eq!(
+        lines.collect::<Vec<_>>(),
+        [
+            vec!["64", "0"],
+            vec!["5"],
+            vec!["100"],
+            vec!["NoNewPrivs:", "1"],
+            vec!["Cpus_allowed_list:", "0"],
+        ]
+    );
+}
+
+#[test]
+fn subreaper_fails_before_exec() {
+    let temp_dir = testing::tempdir();
+    let touched = temp_dir.path().join("touched");
+    let touch = |args: &[&str]| {
+        Command::new(subreaper())
+            .args(args)
+            .arg("--")
+            .arg("touch")
+            .arg(&touched)
+            .env("SUBREAPER_LOG", "off")
+            .status()
+            .unwrap()
+    };
+
+    // Raising the hard limit of open files to unlimited is never permitted.
+    assert_eq!(touch(&["--rlimit", "nofile=unlimited"]).code(), Some(1));
+    assert_eq!(touch(&["--user", "no such user"]).code(), Some(1));
+    assert!(!touched.exists(), "the child should never be exec'ed");
+
+    assert_eq!(touch(&["--rlimit", "nofile=256"]).code(), Some(0));
+    assert!(touched.exists());
+}
+
+#[test]
+fn subreaper_runs_the_child_as_user() {
+    if unsafe { libc::geteuid() } != 0 {
+        eprintln!("skipped: not running as root");
+        return;
+    }
+    let id = |args: &[&str]| {
+        let (stdout, _) =
+            output(&[&["--output=passthrough"], args].concat(), "id -u; id -g; id -G");
+        String::from_utf8(stdout).unwrap()
+    };
+
+    // A uid without an entry in the user database keeps the primary group.
+    let gid = unsafe { libc::getegid() };
+    assert_eq!(id(&["--user", "12345"]), format!("12345\n{gid}\n{gid}\n"));
+    assert_eq!(id(&["--user", "12345", "--group", "54321"]), "12345\n54321\n54321\n");
+
+    // Without CAP_SETGID, the primary group can be set to the user's own one.
+    let out = Command::new("setpriv")
+        .args(["--reuid", "12345", "--regid", "12345", "--clear-groups"])
+        .arg(subreaper())
+        .args(["--output=passthrough", "--group", "12345", "--", "id", "-G"])
+        .env("SUBREAPER_LOG", "off")
+        .output();
+    match out {
+        Ok(out) => assert_eq!(String::from_utf8_lossy(&out.stdout), "12345\n", "{out:?}"),
+        Err(err) => eprintln!("skipped setpriv: {err}"),
+    }
+}
+
+#[test]
+fn subreaper_runs_the_child_under_pty() {
+    let tty = subreaper_test::tty();
+    let script = format!("exec {}", tty.display());
+    let (stdout, _) = output(&["--pty", "--output=passthrough"], &script);
+    let stdout = String::from_utf8(stdout).unwrap();
+    assert!(
+        stdout.starts_with("Tty\tstdin=1\tstdout=1\tstderr=1\tsession_leader=1\tforeground=1\n"),
+        "{stdout}"
+    );
+
+    let (stdout, _) = output(&["--pty", "--output=prefix"], "echo out; echo err >&2");
+    let stdout = String::from_utf8(stdout).unwrap();
+    let lines = stdout.lines().map(|line| line.split_once(' ').unwrap().1).collect::<Vec<_>>();
+    assert_eq!(lines, ["stdout: out", "stdout: err"], "no CRLF translation");
+}
+
+#[test]
+fn subreaper_propagates_window_size_to_pty() {
+    use std::os::fd::FromRawFd;
+
+    let mut size = libc::winsize { ws_row: 30, ws_col: 100, ws_xpixel: 0, ws_ypixel: 0 };
+    let (mut master, mut slave) = (0, 0);
+    let ret = unsafe {
+        libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), &size)
+    };
+    assert_eq!(ret, 0);
+
+    let mut child = Command::new(subreaper())
+        .args(["--pty", "--output=passthrough", "--"])
+        .arg(subreaper_test::tty())
+        .arg("--wait-resize")
+        // The window size is taken from the terminal of subreaper.
+        .stdin(unsafe { Stdio::from_raw_fd(slave) })
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    assert!(lines.next().unwrap().unwrap().starts_with("Tty\t"));
+    assert_eq!(lines.next().unwrap().unwrap(), "Size\trows=30\tcols=100");
+
+    size.ws_row = 40;
+    assert_eq!(unsafe { libc::ioctl(master, libc::TIOCSWINSZ, &size) }, 0);
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGWINCH) };
+    assert_eq!(lines.next().unwrap().unwrap(), "Size\trows=40\tcols=100");
+
+    assert!(child.wait().unwrap().success());
+    unsafe { libc::close(master) };
+}
+
+#[test]
+fn subreaper_is_controlled_via_socket() {
+    let temp_dir = testing::tempdir();
+    let socket = temp_dir.path().join("control.sock");
+    let pid_file = temp_dir.path().join("subreaper.pid");
+
+    let mut child = Command::new(subreaper())
+        .arg("--control-socket")
+        .arg(&socket)
+        .arg("--pid-file")
+        .arg(&pid_file)
+        .args(["--", "sh", "-c", "sleep 30 & wait"])
+        .spawn()
+        .unwrap();
+    let ctl = |command: &[&str]| {
+        let out = Command::new(subreaper())
+            .arg("ctl")
+            .arg("--socket")
+            .arg(&socket)
+            .args(command)
+            .output()
+            .unwrap();
+        let response = serde_json::from_slice::<serde_json::Value>(&out.stdout).unwrap();
+        assert_eq!(out.status.success(), response["ok"] == true, "{response}");
+        response
+    };
+
+    let started = Instant::now();
+    while !socket.exists() {
+        assert!(started.elapsed() < Duration::from_secs(10), "the control socket is not ready");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+    assert_eq!(pid.trim().parse::<u32>().unwrap(), child.id());
+
+    let status = ctl(&["status"]);
+    assert_eq!(status["pid"], child.id());
+    assert_eq!(status["restarts"], 0);
+    let child_pid = status["child"].as_i64().unwrap();
+
+    // The child and the sleep process.
+    let pids = ctl(&["pids"]);
+    assert_eq!(pids["pids"].as_array().unwrap().len(), 2, "{pids}");
+    assert!(pids["pids"].as_array().unwrap().contains(&child_pid.into()));
+
+    assert_eq!(ctl(&["signal", "CONT", "group"])["group"], true);
+    assert_eq!(ctl(&["signal", "NOSIG"])["ok"], false);
+    assert_eq!(ctl(&["reboot"])["ok"], false);
+
+    assert_eq!(ctl(&["stop"])["ok"], true);
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(143));
+    assert!(!socket.exists());
+    assert!(!pid_file.exists());
+}
+
+#[test]
+fn subreaper_keeps_files_at_control_socket() {
+    let temp_dir = testing::tempdir();
+    let path = temp_dir.path().join("config.toml");
+    std::fs::write(&path, "not a socket\n").unwrap();
+
+    let out = Command::new(subreaper())
+        .arg("--control-socket")
+        .arg(&path)
+        .args(["--", "echo", "spawned"])
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty(), "fails before spawning");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains(&path.display().to_string()), "{stderr}");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket\n");
+}
+
+#[test]
+fn subreaper_maps_exit_codes() {
+    let temp_dir = testing::tempdir();
+    let on_exit = temp_dir.path().join("exited");
+    let report = temp_dir.path().join("report.json");
+    let run = |args: &[&str], script: &str| {
+        let _ = std::fs::remove_file(&on_exit);
+        let _ = std::fs::remove_file(on_exit.with_extension("err"));
+        let status = Command::new(subreaper())
+            .arg("--on-exit")
+            .arg(&on_exit)
+            .arg("--report")
+            .arg(&report)
+            .args(args)
+            .args(["--", "sh", "-c", script])
+            .status()
+            .unwrap();
+        let report = serde_json::from_slice::<serde_json::Value>(&std::fs::read(&report).unwrap());
+        let report = report.unwrap();
+        assert_eq!(report["exit_code"], status.code().unwrap(), "{report}");
+        assert_eq!(on_exit.exists(), status.success(), "the marker follows the exit code");
+        assert_eq!(on_exit.with_extension("err").exists(), !status.success());
+        (status.code().unwrap(), report["cause"].as_str().unwrap().to_owned())
+    };
+
+    assert_eq!(run(&[], "exit 3"), (3, "3".to_owned()));
+    assert_eq!(run(&["--success-exit-codes", "0,3,75"], "exit 3"), (0, "3".to_owned()));
+    assert_eq!(run(&["--success-exit-codes", "0,3,75"], "exit 4"), (4, "4".to_owned()));
+    assert_eq!(run(&["--success-exit-codes", "3"], "exit 0"), (1, "0".to_owned()));
+
+    let map = ["--map-exit", "75=0,timeout=0,signal:KILL=9"];
+    assert_eq!(run(&map, "exit 75"), (0, "75".to_owned()));
+    assert_eq!(run(&map, "kill -KILL $$"), (9, "signal:SIGKILL".to_owned()));
+    let timeout = [&map[..], &["--kill-after", "10ms"]].concat();
+    assert_eq!(run(&timeout, "sleep 10"), (0, "timeout".to_owned()));
+    assert_eq!(run(&["--map-exit", "75=3", "--success-exit-codes", "3"], "exit 75").0, 0);
+}
@@ -0,0 +1,125 @@
+#![allow(missing_docs)]
+
+use std::os::unix::process::ExitStatusExt;
+use std::time::Duration;
+
+use subreaper::Supervisor;
+
+#[tokio::test]
+async fn supervise_in_process() {
+    let temp_dir = testing::tempdir();
+    let on_exit = temp_dir.path().join("on_exit");
+
+    let supervisor = Supervisor::builder()
+        .program("sh")
+        .args(["-c", "test \"$FOO\" = bar && exit 3"])
+        .envs([("FOO", "bar")])
+        .on_exit(&on_exit)
+        .spawn()
+        .await
+        .unwrap();
+    assert!(supervisor.id() > 0);
+
+    let status = supervisor.wait().await.unwrap();
+    assert_eq!(status.exit_status().code(), Some(3));
+    assert_eq!(status.code(), 3);
+    assert!(!status.success());
+    assert!(on_exit.with_extension("err").exists());
+}
+
+#[tokio::test]
+async fn supervise_with_timeout() {
+    let temp_dir = testing::tempdir();
+    let report = temp_dir.path().join("report.json");
+
+    let status = Supervisor::builder()
+        .program("sleep")
+        .args(["10"])
+        .timeout(Duration::from_millis(200))
+        .report(&report)
+        .spawn()
+        .await
+        .unwrap()
+        .wait()
+        .await
+        .unwrap();
+    assert!(status.exit_reasons().timedout());
+    assert_eq!(status.exit_reasons().child_signaled(), Some(libc::SIGTERM));
+    assert_eq!(status.code(), 124);
+    assert!(report.exists());
+
+    let status = Supervisor::builder()
+        .program("sleep")
+        .args(["10"])
+        .timeout(Duration::from_millis(200))
+        .timeout_is_ok()
+        .spawn()
+        .await
+        .unwrap()
+        .wait()
+        .await
+        .unwrap();
+    assert!(status.success());
+}
+
+/// Tests if the process is running, i.e., neither reaped nor a zombie.
+fn is_running(pid: u32) -> bool {
+    std::fs::read_to_string(format!("/proc/{pid}/stat"))
+        .is_ok_and(|stat| stat.rsplit_once(')').is_some_and(|(_, rest)| !rest.starts_with(" Z")))
+}
+
+#[tokio::test]
+async fn supervise_leaves_other_children_alone() {
+    let temp_dir = testing::tempdir();
+    let pid_file = temp_dir.path().join("pid");
+
+    let mut host_child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+
+    // The background sleep is left behind in the process group of the child.
+    let script = format!("sleep 30 & echo $! > {}", pid_file.display());
+    let status = Supervisor::builder()
+        .program("sh")
+        .args(["-c", &script])
+        .spawn()
+        .await
+        .unwrap()
+        .wait()
+        .await
+        .unwrap();
+    assert!(status.success());
+    let left_behind = std::fs::read_to_string(&pid_file).unwrap().trim().parse().unwrap();
+    assert!(!is_running(left_behind), "drained from the process group of the child");
+
+    assert!(is_running(host_child.id()), "not a descendant of the supervised child");
+    host_child.kill().unwrap();
+    assert!(host_child.wait().unwrap().signal().is_some(), "still waitable by its owner");
+}
+
+#[tokio::test]
+async fn supervise_passes_output_through() {
+    // Runs in a copy of this test binary, whose stdout is inspected.
+    if std::env::var_os("SUPERVISE_OUTPUT").is_some() {
+        let status = Supervisor::builder()
+            .program("sh")
+            .args(["-c", "echo passed through; echo logged >&2"])
+            .spawn()
+            .await
+            .unwrap()
+            .wait()
+            .await
+            .unwrap();
+        assert!(status.success());
+        return;
+    }
+
+    let out = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "supervise_passes_output_through"])
+        .env("SUPERVISE_OUTPUT", "1")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stdout.contains("passed through\n"), "{stdout}");
+    assert!(stderr.contains("logged\n"), "{stderr}");
+}
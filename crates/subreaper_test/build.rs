@@ -9,14 +9,14 @@ use std::{
 
 fn main() -> io::Result<()> {
     println!("cargo:rerun-if-env-changed=CC");
-    println!("cargo:rerun-if-changed=orphan.c");
 
-    let status = Command::new("cc")
-        .arg("orphan.c")
-        .arg("-o")
-        .arg(PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR not set")).join("orphan"))
-        .status()?;
-    assert!(status.success());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR not set"));
+    for bin in ["orphan", "tty"] {
+        println!("cargo:rerun-if-changed={bin}.c");
+        let status =
+            Command::new("cc").arg(format!("{bin}.c")).arg("-o").arg(out_dir.join(bin)).status()?;
+        assert!(status.success());
+    }
 
     Ok(())
 }
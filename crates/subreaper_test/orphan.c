@@ -2,40 +2,46 @@
 #include <stdlib.h>
 #include <unistd.h>
 
-// Creates orphan process to be reaped.
-int main() {
+// Creates orphan processes to be reaped.
+//
+// The number of orphans can be given as the first argument, defaults to 1.
+int main(int argc, char *argv[]) {
     pid_t pid    = getpid();
     pid_t group  = getpgid(0);
     pid_t parent = getppid();
     pid_t child;
+    int   orphans = argc > 1 ? atoi(argv[1]) : 1;
 
-    if ((child = fork()) < 0) {
-        perror("could not create a child process");
-        exit(1);
-    }
+    for (int i = 0; i < orphans; i++) {
+        if ((child = fork()) < 0) {
+            perror("could not create a child process");
+            exit(1);
+        }
 
-    if (child > 0) {
-        fprintf(
-            stdout,
-            "Parent\tpid=%d\tgroup=%d\tparent=%d\n",
-            pid,
-            group,
-            parent
-        );
-        fflush(stdout);
-        exit(0);
-    } else {
-        // Wait to be reparented.
-        while (getppid() != parent) {
+        if (child == 0) {
+            // Wait to be reparented.
+            while (getppid() == pid) {
+                usleep(1000);
+            }
+            fprintf(
+                stdout,
+                "Child\tpid=%d\tgroup=%d\tparent=%d\n",
+                getpid(),
+                getpgid(0),
+                getppid()
+            );
+            fflush(stdout);
+            exit(0);
         }
-        fprintf(
-            stdout,
-            "Child\tpid=%d\tgroup=%d\tparent=%d\n",
-            getpid(),
-            getpgid(0),
-            getppid()
-        );
-        fflush(stdout);
-        exit(0);
     }
+
+    fprintf(
+        stdout,
+        "Parent\tpid=%d\tgroup=%d\tparent=%d\n",
+        pid,
+        group,
+        parent
+    );
+    fflush(stdout);
+    exit(0);
 }
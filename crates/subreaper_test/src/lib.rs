@@ -3,6 +3,7 @@
 use std::path::PathBuf;
 
 static ORPHAN: &str = concat!(env!("OUT_DIR"), "/orphan");
+static TTY: &str = concat!(env!("OUT_DIR"), "/tty");
 
 /// Returns the path to the executable `orphan`.
 pub fn orphan() -> PathBuf {
@@ -10,3 +11,10 @@ pub fn orphan() -> PathBuf {
     assert!(path.exists());
     path
 }
+
+/// Returns the path to the executable `tty`, which reports its controlling terminal.
+pub fn tty() -> PathBuf {
+    let path = PathBuf::from(TTY);
+    assert!(path.exists());
+    path
+}
@@ -0,0 +1,50 @@
+#include <signal.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/ioctl.h>
+#include <unistd.h>
+
+static volatile sig_atomic_t resized = 0;
+
+static void on_winch(int signum) {
+    (void)signum;
+    resized = 1;
+}
+
+// Prints the window size of the controlling terminal.
+static void print_size(void) {
+    struct winsize size;
+    memset(&size, 0, sizeof(size));
+    ioctl(STDIN_FILENO, TIOCGWINSZ, &size);
+    fprintf(stdout, "Size\trows=%d\tcols=%d\n", size.ws_row, size.ws_col);
+    fflush(stdout);
+}
+
+// Reports how the process is attached to its terminal.
+//
+// With `--wait-resize` as the first argument, waits for SIGWINCH and prints the new window size.
+int main(int argc, char *argv[]) {
+    struct sigaction action;
+    memset(&action, 0, sizeof(action));
+    action.sa_handler = on_winch;
+    sigaction(SIGWINCH, &action, NULL);
+
+    fprintf(
+        stdout,
+        "Tty\tstdin=%d\tstdout=%d\tstderr=%d\tsession_leader=%d\tforeground=%d\n",
+        isatty(STDIN_FILENO),
+        isatty(STDOUT_FILENO),
+        isatty(STDERR_FILENO),
+        getsid(0) == getpid(),
+        tcgetpgrp(STDIN_FILENO) == getpgid(0));
+    print_size();
+
+    if (argc > 1 && strcmp(argv[1], "--wait-resize") == 0) {
+        while (!resized) {
+            pause();
+        }
+        print_size();
+    }
+
+    return 0;
+}